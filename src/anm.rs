@@ -0,0 +1,971 @@
+use std::io::{Cursor, Write};
+
+use byteorder::{WriteBytesExt, LE};
+use glam::*;
+
+use crate::error::{ByteReader, Error, Result};
+use crate::packed_quat;
+
+/// Track type of packed quaternion rotations.
+pub const TRACK_ROTATION: u16 = 28;
+/// Track type of half-float translations.
+pub const TRACK_HALF_TRANSLATION: u16 = 30;
+/// Track type of half-float translations added to an `f32` base offset.
+pub const TRACK_HALF_OFFSET_TRANSLATION: u16 = 31;
+/// Track type the writer gives `f32` translations. `Dance.cpp` only names
+/// 28, 30 and 31 and reads every other type as `f32`, so any other value
+/// parses the same; a track read with such a type keeps its own.
+pub const TRACK_FLOAT_TRANSLATION: u16 = 29;
+
+/// Largest error, in `.model` units, the writer accepts when it stores a
+/// translation track as half floats instead of `f32`.
+pub const HALF_TRANSLATION_ERROR: f32 = 1e-3;
+
+/// Playback rate of `.anm` frames. The files do not store one; VMD, which
+/// is exported frame for frame, runs at 30 too.
+pub const FRAME_RATE: f32 = 30.0;
+
+fn align_to(v: u64, a: u64) -> u64 {
+    v.div_ceil(a) * a
+}
+
+fn align_reader<T: AsRef<[u8]>>(reader: &mut Cursor<T>) -> Result<()> {
+    reader.seek_to(align_to(reader.position(), 16))
+}
+
+fn read_vec3f<T: AsRef<[u8]>>(reader: &mut Cursor<T>) -> Result<Vec3> {
+    let x = reader.f32()?;
+    let y = reader.f32()?;
+    let z = reader.f32()?;
+    Ok(vec3(x, y, z))
+}
+
+fn read_vec4f<T: AsRef<[u8]>>(reader: &mut Cursor<T>) -> Result<Vec4> {
+    let x = reader.f32()?;
+    let y = reader.f32()?;
+    let z = reader.f32()?;
+    let w = reader.f32()?;
+    Ok(vec4(x, y, z, w))
+}
+
+fn read_vec3h<T: AsRef<[u8]>>(reader: &mut Cursor<T>) -> Result<Vec3> {
+    let x = half::f16::from_bits(reader.u16()?).to_f32();
+    let y = half::f16::from_bits(reader.u16()?).to_f32();
+    let z = half::f16::from_bits(reader.u16()?).to_f32();
+    Ok(vec3(x, y, z))
+}
+
+fn pad_writer(file: &mut Cursor<Vec<u8>>) {
+    let end = align_to(file.position(), 16);
+    while file.position() < end {
+        file.write_u8(0).unwrap();
+    }
+}
+
+fn write_vec3f(file: &mut Cursor<Vec<u8>>, v: Vec3) {
+    file.write_f32::<LE>(v.x).unwrap();
+    file.write_f32::<LE>(v.y).unwrap();
+    file.write_f32::<LE>(v.z).unwrap();
+}
+
+fn write_vec3h(file: &mut Cursor<Vec<u8>>, v: Vec3) {
+    for c in v.to_array() {
+        file.write_u16::<LE>(half::f16::from_f32(c).to_bits()).unwrap();
+    }
+}
+
+/// `v` as read back from half floats.
+fn round_half(v: Vec3) -> Vec3 {
+    v.map(|c| half::f16::from_f32(c).to_f32())
+}
+
+struct TrackHeader {
+    kind: u16,
+    interpolation: u16,
+    count: usize,
+    bone: u16,
+    unknown: u32,
+}
+
+/// Reads a 16-byte track header and, for a sparse track, its frame list,
+/// leaving the reader at the 16-byte aligned start of the values.
+fn read_track_header<T: AsRef<[u8]>>(reader: &mut Cursor<T>) -> Result<(TrackHeader, Vec<u32>)> {
+    let kind = reader.u16()?;
+    let interpolation = reader.u16()?;
+    let count = reader.u16()? as usize;
+    let bone = reader.u16()?;
+    reader.u32()?;
+    let unknown = reader.u32()?;
+    align_reader(reader)?;
+    let mut frames: Vec<u32> = (0..count as u32).collect();
+    if interpolation == 0 {
+        for f in &mut frames {
+            *f = reader.u16()? as u32;
+        }
+        align_reader(reader)?;
+    }
+    Ok((TrackHeader { kind, interpolation, count, bone, unknown }, frames))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Key<T> {
+    pub frame: u32,
+    pub value: T,
+}
+
+/// One track of a `.anm` file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Track<T> {
+    /// Raw track type, one of the `TRACK_*` constants, or any other value
+    /// for `f32` translations.
+    pub kind: u16,
+    /// Raw interpolation field. Zero marks a sparse track whose keys carry
+    /// explicit frames; otherwise there is one key per frame.
+    pub interpolation: u16,
+    pub unknown: u32,
+    /// Keys sorted by frame. Translations of type 31 already include the
+    /// base offset.
+    pub keys: Vec<Key<T>>,
+}
+
+impl<T: Copy> Track<T> {
+    pub fn is_sparse(&self) -> bool {
+        self.interpolation == 0
+    }
+
+    /// Value at `frame` the way the game holds keys: the last key at or
+    /// before `frame`. Before the first key a sparse track has no value and
+    /// a dense one takes its first key.
+    pub fn value_at(&self, frame: u32) -> Option<T> {
+        let i = self.keys.partition_point(|k| k.frame <= frame);
+        match i.checked_sub(1) {
+            Some(i) => Some(self.keys[i].value),
+            None if self.is_sparse() => None,
+            None => self.keys.first().map(|k| k.value),
+        }
+    }
+}
+
+/// Values that can be blended between keys.
+pub trait Interpolate: Copy {
+    fn lerp(a: Self, b: Self, t: f32) -> Self;
+    fn slerp(a: Self, b: Self, t: f32) -> Self {
+        Self::lerp(a, b, t)
+    }
+    /// Whether `a` and `b` differ by no more than `tolerance` allows.
+    fn within(a: Self, b: Self, tolerance: &Tolerance) -> bool;
+}
+
+impl Interpolate for Vec3 {
+    fn lerp(a: Self, b: Self, t: f32) -> Self {
+        a.lerp(b, t)
+    }
+    fn within(a: Self, b: Self, tolerance: &Tolerance) -> bool {
+        a.distance(b) <= tolerance.distance
+    }
+}
+
+impl Interpolate for Quat {
+    /// Normalised lerp along the shorter arc.
+    fn lerp(a: Self, b: Self, t: f32) -> Self {
+        a.lerp(b, t)
+    }
+    fn slerp(a: Self, b: Self, t: f32) -> Self {
+        a.slerp(b, t)
+    }
+    /// Compares the rotation angle between `a` and `b`, taken from the
+    /// chord `|a - b| = 2 sin(angle / 4)`, which unlike `acos` of the dot
+    /// product stays accurate for small angles.
+    fn within(a: Self, b: Self, tolerance: &Tolerance) -> bool {
+        let (a, b) = (a.normalize(), b.normalize());
+        let chord = (a - b).length().min((a + b).length());
+        4.0 * (chord / 2.0).min(1.0).asin() <= tolerance.angle
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interpolation {
+    Step,
+    Linear,
+    /// Spherical for rotations, linear for translations.
+    Slerp,
+}
+
+impl Interpolation {
+    pub fn blend<T: Interpolate>(self, a: T, b: T, t: f32) -> T {
+        match self {
+            Interpolation::Step => a,
+            Interpolation::Linear => T::lerp(a, b, t),
+            Interpolation::Slerp => T::slerp(a, b, t),
+        }
+    }
+}
+
+impl std::str::FromStr for Interpolation {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        match s.to_ascii_lowercase().as_str() {
+            "step" => Ok(Interpolation::Step),
+            "linear" => Ok(Interpolation::Linear),
+            "slerp" => Ok(Interpolation::Slerp),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Wrap {
+    /// Hold the first and last keys outside the motion.
+    #[default]
+    Clamp,
+    /// Repeat the motion every `frame_count` frames, blending the last key
+    /// into the first.
+    Loop,
+}
+
+/// How exporters turn tracks into values at arbitrary times.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sampling {
+    /// `None` keeps each track's own behaviour: step for sparse tracks,
+    /// which the game holds, and slerp for dense ones.
+    pub interpolation: Option<Interpolation>,
+    pub wrap: Wrap,
+    /// Output frame rate. `FRAME_RATE` keeps one sample per `.anm` frame;
+    /// 60 doubles them.
+    pub fps: f32,
+}
+
+impl Default for Sampling {
+    fn default() -> Self {
+        Sampling {
+            interpolation: None,
+            wrap: Wrap::Clamp,
+            fps: FRAME_RATE,
+        }
+    }
+}
+
+impl Sampling {
+    pub fn interpolation_for<T: Copy>(&self, track: &Track<T>) -> Interpolation {
+        match self.interpolation {
+            Some(i) => i,
+            None if track.is_sparse() => Interpolation::Step,
+            None => Interpolation::Slerp,
+        }
+    }
+
+    /// Times, in `.anm` frames, of every output frame for a motion of
+    /// `frame_count` frames. Clamped output ends on the last frame; looped
+    /// output stops one output frame short of where it starts over.
+    pub fn frame_times(&self, frame_count: u32) -> Vec<f32> {
+        let step = FRAME_RATE / self.fps;
+        let n = match self.wrap {
+            Wrap::Clamp => (frame_count.saturating_sub(1) as f32 / step).floor() as u32 + 1,
+            Wrap::Loop => (frame_count as f32 / step).round().max(1.0) as u32,
+        };
+        (0..n).map(|k| k as f32 * step).collect()
+    }
+}
+
+impl<T: Interpolate> Track<T> {
+    /// Value at fractional `time`, in `.anm` frames, for a motion of
+    /// `frame_count` frames. Like `value_at`, a sparse track has no value
+    /// before its first key unless looping wraps round to its last.
+    pub fn sample(&self, time: f32, sampling: &Sampling, frame_count: u32) -> Option<T> {
+        let (first, last) = (self.keys.first()?, self.keys.last()?);
+        let period = frame_count.max(1) as f32;
+        let time = match sampling.wrap {
+            Wrap::Clamp => time,
+            Wrap::Loop => time.rem_euclid(period),
+        };
+        let i = self.keys.partition_point(|k| k.frame as f32 <= time);
+        let (a, b, a_time, b_time) = match (i.checked_sub(1), sampling.wrap) {
+            (Some(i), _) if i + 1 < self.keys.len() => {
+                let (a, b) = (&self.keys[i], &self.keys[i + 1]);
+                (a.value, b.value, a.frame as f32, b.frame as f32)
+            },
+            (Some(_), Wrap::Loop) => (last.value, first.value, last.frame as f32, first.frame as f32 + period),
+            (None, Wrap::Loop) => (last.value, first.value, last.frame as f32 - period, first.frame as f32),
+            (Some(_), Wrap::Clamp) => return Some(last.value),
+            (None, Wrap::Clamp) if self.is_sparse() => return None,
+            (None, Wrap::Clamp) => return Some(first.value),
+        };
+        let t = if b_time > a_time { ((time - a_time) / (b_time - a_time)).clamp(0.0, 1.0) } else { 0.0 };
+        if t == 0.0 {
+            return Some(a);
+        }
+        Some(sampling.interpolation_for(self).blend(a, b, t))
+    }
+}
+
+impl<T: Interpolate> Track<T> {
+    /// Keys as the writer stores them: as they are for a sparse track, and
+    /// for a dense one a key at every frame from 0 to the last key.
+    fn stored_keys(&self, frame_count: u32) -> Vec<Key<T>> {
+        let contiguous = self.keys.iter().enumerate().all(|(i, k)| k.frame as usize == i);
+        let Some(last) = self.keys.last().filter(|_| !self.is_sparse() && !contiguous) else {
+            return self.keys.clone();
+        };
+        let sampling = Sampling::default();
+        (0..=last.frame).filter_map(|f| Some(Key { frame: f, value: self.sample(f as f32, &sampling, frame_count)? })).collect()
+    }
+}
+
+/// Writes a track header like the one `read_track_header` reads, and a
+/// sparse track's frame list, leaving `file` at the start of the values.
+fn write_track_header<T: Copy>(file: &mut Cursor<Vec<u8>>, kind: u16, track: &Track<T>, keys: &[Key<T>], bone: usize) -> Result<()> {
+    let offset = file.position();
+    let Ok(count) = u16::try_from(keys.len()) else {
+        return Err(Error::InvalidValue { offset: offset + 4, what: "track key count" });
+    };
+    file.write_u16::<LE>(kind).unwrap();
+    file.write_u16::<LE>(track.interpolation).unwrap();
+    file.write_u16::<LE>(count).unwrap();
+    file.write_u16::<LE>(bone as u16).unwrap();
+    file.write_u32::<LE>(0).unwrap();
+    file.write_u32::<LE>(track.unknown).unwrap();
+    if track.is_sparse() {
+        for k in keys {
+            let Ok(frame) = u16::try_from(k.frame) else {
+                return Err(Error::InvalidValue { offset: file.position(), what: "track key frame" });
+            };
+            file.write_u16::<LE>(frame).unwrap();
+        }
+        pad_writer(file);
+    }
+    Ok(())
+}
+
+/// Largest error keyframe reduction may introduce at a dropped key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tolerance {
+    /// Rotation angle in radians.
+    pub angle: f32,
+    /// Distance in the units of the exported file.
+    pub distance: f32,
+}
+
+/// Key counts of one exported track before and after reduction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reduction {
+    pub track: String,
+    pub before: usize,
+    pub after: usize,
+}
+
+impl Reduction {
+    /// How many times fewer keys the track has.
+    pub fn ratio(&self) -> f32 {
+        self.before as f32 / self.after.max(1) as f32
+    }
+}
+
+/// Indices of the keys to keep out of `len`: the first, the last, and
+/// between them as few as `fits(a, b, i)` allows, which tells whether key
+/// `i` is rebuilt well enough by interpolating kept keys `a` and `b`.
+///
+/// Each span is grown by doubling and then bisection, so a long still
+/// stretch costs `O(n log n)` checks rather than `O(n²)`. That may end a
+/// span early where a longer one would fit again, but every span kept is
+/// checked against all the keys it drops.
+pub fn reduce_keys_by(len: usize, fits: impl Fn(usize, usize, usize) -> bool) -> Vec<usize> {
+    let Some(last) = len.checked_sub(1) else {
+        return Vec::new();
+    };
+    let mut kept = vec![0];
+    let mut a = 0;
+    while a < last {
+        let spans = |b: usize| (a + 1..b).all(|i| fits(a, b, i));
+        let (mut good, mut bad) = (a + 1, None);
+        let mut step = 2;
+        while bad.is_none() && good < last {
+            let b = (a + step).min(last);
+            if spans(b) { good = b } else { bad = Some(b) }
+            step *= 2;
+        }
+        if let Some(mut bad) = bad {
+            while bad - good > 1 {
+                let mid = (good + bad) / 2;
+                if spans(mid) { good = mid } else { bad = mid }
+            }
+        }
+        kept.push(good);
+        a = good;
+    }
+    kept
+}
+
+/// [`reduce_keys_by`] for `values` keyed at `times` and played back with
+/// `interpolation`.
+pub fn reduce_keys<T: Interpolate>(times: &[f32], values: &[T], interpolation: Interpolation, tolerance: &Tolerance) -> Vec<usize> {
+    reduce_keys_by(values.len(), |a, b, i| {
+        let t = (times[i] - times[a]) / (times[b] - times[a]);
+        T::within(interpolation.blend(values[a], values[b], t), values[i], tolerance)
+    })
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BoneTracks {
+    pub rotation: Option<Track<Quat>>,
+    pub translation: Option<Track<Vec3>>,
+}
+
+/// A dance motion parsed from a `.anm` file. Bone `i` is bone `i` of the
+/// matching `.model`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Animation {
+    /// Last frame of the motion; frames run from 0 to `max_frame` inclusive.
+    pub max_frame: u32,
+    pub bones: Vec<BoneTracks>,
+    /// The bytes before the track address table, which `Dance.cpp` skips
+    /// over. Kept so that [`Animation::write`] can put them back.
+    pub header: Vec<u8>,
+}
+
+impl Animation {
+    /// Port of `ReadDanceAnm` from `Dance.cpp`. Unlike that node, values
+    /// are kept exactly as decoded instead of snapping near-zero components
+    /// to zero.
+    pub fn read(content: Vec<u8>) -> Result<Self> {
+        let mut reader = Cursor::new(content);
+        reader.seek_to(0x04)?;
+        let max_frame = reader.u32()?;
+        reader.seek_to(0x20)?;
+        let bone_count = reader.u32()? as u64;
+        let Some(track_count) = bone_count.checked_sub(3).map(|n| n * 2) else {
+            return Err(Error::InvalidValue { offset: 0x20, what: "bone count" });
+        };
+        let section2 = 0x24 + 8 + 2 * bone_count;
+        reader.seek_to(section2 + 8)?;
+        let mut addrs = Vec::new();
+        for _ in 0..track_count {
+            addrs.push(reader.u32()? as u64);
+        }
+
+        let mut anm = Animation {
+            max_frame,
+            bones: vec![BoneTracks::default(); bone_count as usize],
+            header: reader.get_ref()[..section2 as usize + 8].to_vec(),
+        };
+        for addr in addrs {
+            let offset = section2 + addr;
+            reader.seek_to(offset)?;
+            let (header, frames) = read_track_header(&mut reader)?;
+            let TrackHeader { kind, interpolation, count, bone, unknown } = header;
+            let Some(tracks) = anm.bones.get_mut(bone as usize) else {
+                return Err(Error::InvalidValue { offset: offset + 6, what: "track bone index" });
+            };
+            let sparse = interpolation == 0;
+
+            if kind == TRACK_ROTATION {
+                if tracks.rotation.is_some() {
+                    return Err(Error::InvalidValue { offset, what: "duplicate rotation track" });
+                }
+                let mut values = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let mut data = [0u8; 6];
+                    data.copy_from_slice(&reader.read_bytes(6)?);
+                    values.push(packed_quat::decode(data));
+                }
+                tracks.rotation = Some(Track { kind, interpolation, unknown, keys: make_keys(frames, values) });
+            } else {
+                if tracks.translation.is_some() {
+                    return Err(Error::InvalidValue { offset, what: "duplicate translation track" });
+                }
+                // Dense type 31 tracks carry no base offset and are read as
+                // plain f32, as in `Dance.cpp`.
+                let base = if sparse && kind == TRACK_HALF_OFFSET_TRANSLATION {
+                    Some(read_vec3f(&mut reader)?)
+                } else {
+                    None
+                };
+                let mut values = Vec::with_capacity(count);
+                for _ in 0..count {
+                    values.push(match (kind, base) {
+                        (TRACK_HALF_TRANSLATION, _) => read_vec3h(&mut reader)?,
+                        (_, Some(base)) => read_vec3h(&mut reader)? + base,
+                        _ => read_vec3f(&mut reader)?,
+                    });
+                }
+                tracks.translation = Some(Track { kind, interpolation, unknown, keys: make_keys(frames, values) });
+            }
+        }
+        Ok(anm)
+    }
+
+    /// Encodes the motion in the layout [`Animation::read`] parses: the
+    /// kept header with the frame and bone counts filled in, a table of
+    /// track addresses, then each bone's rotation and translation track at
+    /// a 16-byte boundary. The header is zeroed unless its length matches
+    /// the bone count.
+    ///
+    /// Tracks keep their sparse or dense layout. A dense track missing keys
+    /// is filled in at every frame up to its last key with
+    /// [`Track::sample`]. Rotations are packed quaternions; translations
+    /// take the smallest of half floats, half floats around a base offset
+    /// (sparse tracks only) and `f32` that stays within
+    /// [`HALF_TRANSLATION_ERROR`].
+    ///
+    /// `Dance.cpp` reads exactly `2 * (bone_count - 3)` tracks, so the motion
+    /// must have that many, and track sizes, frames and bone indices must
+    /// fit in 16 bits.
+    pub fn write(&self) -> Result<Vec<u8>> {
+        let bone_count = self.bones.len();
+        if !(3..=u16::MAX as usize + 1).contains(&bone_count) {
+            return Err(Error::InvalidValue { offset: 0x20, what: "bone count" });
+        }
+        let section2 = 0x24 + 8 + 2 * bone_count as u64;
+        let track_count: usize = self.bones.iter()
+            .map(|b| b.rotation.is_some() as usize + b.translation.is_some() as usize)
+            .sum();
+        if track_count != 2 * (bone_count - 3) {
+            return Err(Error::InvalidValue { offset: section2 + 8, what: "track count (must be 2 * (bone count - 3))" });
+        }
+
+        let mut header = vec![0u8; section2 as usize + 8];
+        if self.header.len() == header.len() {
+            header.copy_from_slice(&self.header);
+        }
+        header[0x04..0x08].copy_from_slice(&self.max_frame.to_le_bytes());
+        header[0x20..0x24].copy_from_slice(&(bone_count as u32).to_le_bytes());
+        let mut file = Cursor::new(header);
+        file.set_position(section2 + 8);
+        for _ in 0..track_count {
+            file.write_u32::<LE>(0).unwrap();
+        }
+        pad_writer(&mut file);
+
+        let mut addrs = Vec::with_capacity(track_count);
+        let frame_count = self.frame_count();
+        for (bone, tracks) in self.bones.iter().enumerate() {
+            if let Some(track) = &tracks.rotation {
+                addrs.push(file.position() - section2);
+                let keys = track.stored_keys(frame_count);
+                write_track_header(&mut file, TRACK_ROTATION, track, &keys, bone)?;
+                for k in &keys {
+                    file.write_all(&packed_quat::encode(k.value)).unwrap();
+                }
+                pad_writer(&mut file);
+            }
+            if let Some(track) = &tracks.translation {
+                addrs.push(file.position() - section2);
+                let keys = track.stored_keys(frame_count);
+                let values: Vec<Vec3> = keys.iter().map(|k| k.value).collect();
+                let fits = |base: Vec3| values.iter().all(|&v| (round_half(v - base) + base).distance(v) <= HALF_TRANSLATION_ERROR);
+                let (min, max) = values.iter().fold((Vec3::INFINITY, Vec3::NEG_INFINITY), |(a, b), &v| (a.min(v), b.max(v)));
+                let base = round_half((min + max) / 2.0).map(|c| if c.is_finite() { c } else { 0.0 });
+                if fits(Vec3::ZERO) {
+                    write_track_header(&mut file, TRACK_HALF_TRANSLATION, track, &keys, bone)?;
+                    values.iter().for_each(|&v| write_vec3h(&mut file, v));
+                } else if track.is_sparse() && fits(base) {
+                    write_track_header(&mut file, TRACK_HALF_OFFSET_TRANSLATION, track, &keys, bone)?;
+                    write_vec3f(&mut file, base);
+                    values.iter().for_each(|&v| write_vec3h(&mut file, v - base));
+                } else {
+                    let kind = match track.kind {
+                        TRACK_ROTATION | TRACK_HALF_TRANSLATION | TRACK_HALF_OFFSET_TRANSLATION => TRACK_FLOAT_TRANSLATION,
+                        kind => kind,
+                    };
+                    write_track_header(&mut file, kind, track, &keys, bone)?;
+                    values.iter().for_each(|&v| write_vec3f(&mut file, v));
+                }
+                pad_writer(&mut file);
+            }
+        }
+
+        for (i, addr) in addrs.into_iter().enumerate() {
+            let Ok(addr) = u32::try_from(addr) else {
+                return Err(Error::InvalidValue { offset: section2 + 8 + 4 * i as u64, what: "track address" });
+            };
+            file.set_position(section2 + 8 + 4 * i as u64);
+            file.write_u32::<LE>(addr).unwrap();
+        }
+        Ok(file.into_inner())
+    }
+
+    pub fn frame_count(&self) -> u32 {
+        self.max_frame.saturating_add(1)
+    }
+
+    /// Rotation of `bone` at `frame`, identity where it has no key.
+    pub fn rotation_at(&self, bone: usize, frame: u32) -> Quat {
+        self.bones.get(bone)
+            .and_then(|b| b.rotation.as_ref())
+            .and_then(|t| t.value_at(frame))
+            .unwrap_or(Quat::IDENTITY)
+    }
+
+    /// Translation of `bone` at `frame`, zero where it has no key.
+    pub fn translation_at(&self, bone: usize, frame: u32) -> Vec3 {
+        self.bones.get(bone)
+            .and_then(|b| b.translation.as_ref())
+            .and_then(|t| t.value_at(frame))
+            .unwrap_or(Vec3::ZERO)
+    }
+}
+
+/// The stage camera, parsed from a camera `.anm` file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Camera {
+    /// World orientation of the camera, stored as plain `f32` quaternions.
+    pub rotation: Track<Quat>,
+    /// World position of the camera. Each key is padded to 16 bytes.
+    pub position: Track<Vec3>,
+}
+
+impl Camera {
+    /// Port of `ReadDanceCamera` from `Dance.cpp`: a rotation track at 0x38
+    /// followed by a position track at the next 16-byte boundary.
+    pub fn read(content: Vec<u8>) -> Result<Self> {
+        let mut reader = Cursor::new(content);
+        reader.seek_to(0x38)?;
+        let (header, frames) = read_track_header(&mut reader)?;
+        let mut values = Vec::with_capacity(header.count);
+        for _ in 0..header.count {
+            values.push(Quat::from_vec4(read_vec4f(&mut reader)?));
+        }
+        let rotation = Track {
+            kind: header.kind,
+            interpolation: header.interpolation,
+            unknown: header.unknown,
+            keys: make_keys(frames, values),
+        };
+
+        align_reader(&mut reader)?;
+        let (header, frames) = read_track_header(&mut reader)?;
+        let mut values = Vec::with_capacity(header.count);
+        for _ in 0..header.count {
+            values.push(read_vec3f(&mut reader)?);
+            reader.f32()?;
+        }
+        let position = Track {
+            kind: header.kind,
+            interpolation: header.interpolation,
+            unknown: header.unknown,
+            keys: make_keys(frames, values),
+        };
+        Ok(Camera { rotation, position })
+    }
+
+    /// Frames up to and including the last key of either track.
+    pub fn frame_count(&self) -> u32 {
+        let rot = self.rotation.keys.last().map_or(0, |k| k.frame + 1);
+        let pos = self.position.keys.last().map_or(0, |k| k.frame + 1);
+        rot.max(pos)
+    }
+
+    /// Camera orientation at `frame`, identity before the first key.
+    pub fn rotation_at(&self, frame: u32) -> Quat {
+        self.rotation.value_at(frame).unwrap_or(Quat::IDENTITY)
+    }
+
+    /// Camera position at `frame`, the origin before the first key.
+    pub fn position_at(&self, frame: u32) -> Vec3 {
+        self.position.value_at(frame).unwrap_or(Vec3::ZERO)
+    }
+}
+
+/// Pairs frames with values, sorted by frame. For repeated frames the later
+/// key wins, as it does in the `std::map` that `Dance.cpp` fills.
+fn make_keys<T: Copy>(frames: Vec<u32>, values: Vec<T>) -> Vec<Key<T>> {
+    let mut keys: Vec<Key<T>> = frames.into_iter().zip(values).map(|(frame, value)| Key { frame, value }).collect();
+    keys.sort_by_key(|k| k.frame);
+    let mut deduped: Vec<Key<T>> = Vec::with_capacity(keys.len());
+    for k in keys {
+        match deduped.last_mut() {
+            Some(last) if last.frame == k.frame => *last = k,
+            _ => deduped.push(k),
+        }
+    }
+    deduped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track<T: Copy>(kind: u16, sparse: bool, keys: &[(u32, T)]) -> Track<T> {
+        Track {
+            kind,
+            interpolation: if sparse { 0 } else { 1 },
+            unknown: 0,
+            keys: keys.iter().map(|&(frame, value)| Key { frame, value }).collect(),
+        }
+    }
+
+    /// A motion with `bones` after the three bones `Dance.cpp` skips, whose
+    /// header is already what `write` fills in.
+    fn animation(max_frame: u32, tracks: Vec<BoneTracks>) -> Animation {
+        let mut bones = vec![BoneTracks::default(); 3];
+        bones.extend(tracks);
+        let mut header = vec![0u8; 0x24 + 8 + 2 * bones.len() + 8];
+        header[0x04..0x08].copy_from_slice(&max_frame.to_le_bytes());
+        header[0x20..0x24].copy_from_slice(&(bones.len() as u32).to_le_bytes());
+        Animation { max_frame, bones, header }
+    }
+
+    /// A rotation the packed format stores exactly.
+    fn packed(axis: Vec3, angle: f32) -> Quat {
+        packed_quat::decode(packed_quat::encode(Quat::from_axis_angle(axis.normalize(), angle)))
+    }
+
+    fn rotation(sparse: bool, keys: &[(u32, Quat)]) -> Track<Quat> {
+        track(TRACK_ROTATION, sparse, keys)
+    }
+
+    fn round_trip(anm: &Animation) -> Animation {
+        let data = anm.write().unwrap();
+        let read = Animation::read(data.clone()).unwrap();
+        assert_eq!(read.write().unwrap(), data, "second write differs");
+        read
+    }
+
+    fn translation_kind(anm: &Animation, bone: usize) -> u16 {
+        anm.bones[bone].translation.as_ref().unwrap().kind
+    }
+
+    #[test]
+    fn rotation_tracks_round_trip() {
+        let sparse = rotation(true, &[(0, packed(Vec3::X, 0.5)), (7, packed(Vec3::Y, -1.0)), (20, packed(Vec3::ONE, 2.0))]);
+        let dense = rotation(false, &(0..21).map(|f| (f, packed(vec3(1.0, 2.0, 3.0), f as f32 * 0.1))).collect::<Vec<_>>());
+        let trans = track(TRACK_HALF_TRANSLATION, false, &[(0, Vec3::ZERO)]);
+        let anm = animation(20, vec![
+            BoneTracks { rotation: Some(sparse), translation: Some(trans.clone()) },
+            BoneTracks { rotation: Some(dense), translation: Some(trans) },
+        ]);
+        assert_eq!(round_trip(&anm), anm);
+    }
+
+    #[test]
+    fn translation_tracks_round_trip() {
+        let rot = rotation(false, &[(0, packed(Vec3::X, 0.0))]);
+        // Exact in half floats.
+        let half = track(TRACK_HALF_TRANSLATION, false, &[(0, vec3(0.5, -1.25, 3.0)), (1, vec3(0.75, -1.5, 2.0))]);
+        // Exact as halves around a base of 1000, but not as plain halves.
+        let offset = track(TRACK_HALF_OFFSET_TRANSLATION, true, &[(0, vec3(1000.0, 0.0, -1000.0)), (5, vec3(1000.25, 0.5, -1000.5)), (9, vec3(1000.5, 1.0, -1000.25))]);
+        // Too wide a range for halves around any base.
+        let float = track(7, true, &[(0, vec3(0.1, 2000.3, -5.7)), (3, vec3(5000.3, -0.001, 1e-6))]);
+        let anm = animation(9, vec![
+            BoneTracks { rotation: Some(rot.clone()), translation: Some(half) },
+            BoneTracks { rotation: Some(rot.clone()), translation: Some(offset) },
+            BoneTracks { rotation: Some(rot), translation: Some(float) },
+        ]);
+        assert_eq!(round_trip(&anm), anm);
+    }
+
+    #[test]
+    fn translation_type_follows_half_error() {
+        let rot = rotation(false, &[(0, packed(Vec3::X, 0.0))]);
+        let bone = |t| BoneTracks { rotation: Some(rot.clone()), translation: Some(t) };
+        // Halves are 2^-8 apart in [4, 8), so these miss 4 by a little
+        // less and a little more than the error allowed.
+        let below = vec3(4.0 + 0.9 * HALF_TRANSLATION_ERROR, 0.0, 0.0);
+        let above = vec3(4.0 + 1.1 * HALF_TRANSLATION_ERROR, 0.0, 0.0);
+        let anm = animation(0, vec![
+            bone(track(TRACK_HALF_OFFSET_TRANSLATION, false, &[(0, below)])),
+            bone(track(TRACK_HALF_TRANSLATION, false, &[(0, above)])),
+            bone(track(TRACK_HALF_TRANSLATION, true, &[(0, above)])),
+        ]);
+        let read = round_trip(&anm);
+        assert_eq!(translation_kind(&read, 3), TRACK_HALF_TRANSLATION);
+        let value = read.bones[3].translation.as_ref().unwrap().keys[0].value;
+        assert!(value.distance(below) <= HALF_TRANSLATION_ERROR);
+        // Dense tracks cannot carry a base offset, so fall back to f32.
+        assert_eq!(translation_kind(&read, 4), TRACK_FLOAT_TRANSLATION);
+        assert_eq!(read.bones[4].translation.as_ref().unwrap().keys[0].value, above);
+        assert_eq!(translation_kind(&read, 5), TRACK_HALF_OFFSET_TRANSLATION);
+        let value = read.bones[5].translation.as_ref().unwrap().keys[0].value;
+        assert!(value.distance(above) <= HALF_TRANSLATION_ERROR);
+    }
+
+    #[test]
+    fn dense_track_missing_frames_is_filled() {
+        let (a, b) = (packed(Vec3::Z, 0.0), packed(Vec3::Z, 1.0));
+        let anm = animation(4, vec![BoneTracks {
+            rotation: Some(rotation(false, &[(0, a), (2, b)])),
+            translation: Some(track(TRACK_HALF_TRANSLATION, false, &[(0, Vec3::ZERO), (4, Vec3::ONE)])),
+        }]);
+        let read = round_trip(&anm);
+        let rot = &read.bones[3].rotation.as_ref().unwrap().keys;
+        assert_eq!(rot.iter().map(|k| k.frame).collect::<Vec<_>>(), [0, 1, 2]);
+        assert_eq!((rot[0].value, rot[2].value), (a, b));
+        assert!(Quat::within(rot[1].value, a.slerp(b, 0.5), &Tolerance { angle: packed_quat::MAX_ANGLE_ERROR, distance: 0.0 }));
+        let trans = &read.bones[3].translation.as_ref().unwrap().keys;
+        assert_eq!(trans.iter().map(|k| k.value.x).collect::<Vec<_>>(), [0.0, 0.25, 0.5, 0.75, 1.0]);
+    }
+
+    #[test]
+    fn wrong_track_count_is_rejected() {
+        let rot = rotation(false, &[(0, packed(Vec3::X, 0.0))]);
+        let mut anm = animation(0, vec![BoneTracks { rotation: Some(rot), translation: None }]);
+        assert!(matches!(anm.write(), Err(Error::InvalidValue { what, .. }) if what.starts_with("track count")));
+        anm.bones.truncate(2);
+        assert!(matches!(anm.write(), Err(Error::InvalidValue { offset: 0x20, what: "bone count" })));
+    }
+
+    fn sampling(interpolation: Option<Interpolation>, wrap: Wrap) -> Sampling {
+        Sampling { interpolation, wrap, fps: FRAME_RATE }
+    }
+
+    #[test]
+    fn sample_interpolates_fractional_times() {
+        let trans = track(TRACK_HALF_TRANSLATION, false, &[(0, Vec3::ZERO), (1, vec3(2.0, 0.0, 0.0)), (3, vec3(2.0, 4.0, 0.0))]);
+        let at = |i, time| trans.sample(time, &sampling(i, Wrap::Clamp), 4).unwrap();
+        assert_eq!(at(Some(Interpolation::Step), 0.75), Vec3::ZERO);
+        assert_eq!(at(Some(Interpolation::Linear), 0.25), vec3(0.5, 0.0, 0.0));
+        assert_eq!(at(Some(Interpolation::Slerp), 2.5), vec3(2.0, 3.0, 0.0));
+        // Dense tracks default to slerp, which is lerp for translations.
+        assert_eq!(at(None, 2.0), vec3(2.0, 2.0, 0.0));
+
+        let quarter = Quat::from_rotation_z(std::f32::consts::FRAC_PI_2);
+        let rot = track(TRACK_ROTATION, false, &[(0, Quat::IDENTITY), (1, quarter)]);
+        let at = |i, time| rot.sample(time, &sampling(i, Wrap::Clamp), 2).unwrap();
+        assert!(at(None, 0.5).abs_diff_eq(Quat::from_rotation_z(std::f32::consts::FRAC_PI_4), 1e-6));
+        assert!(at(Some(Interpolation::Slerp), 0.25).abs_diff_eq(Quat::from_rotation_z(std::f32::consts::FRAC_PI_8), 1e-6));
+        let nlerp = at(Some(Interpolation::Linear), 0.25);
+        assert!(nlerp.abs_diff_eq(Quat::IDENTITY.lerp(quarter, 0.25), 1e-6));
+        assert!(!nlerp.abs_diff_eq(Quat::from_rotation_z(std::f32::consts::FRAC_PI_8), 1e-3));
+        assert_eq!(at(Some(Interpolation::Step), 0.99), Quat::IDENTITY);
+
+        // Sparse tracks hold their keys by default.
+        let sparse = track(TRACK_HALF_TRANSLATION, true, &[(0, Vec3::ZERO), (4, Vec3::ONE)]);
+        assert_eq!(sparse.sample(3.9, &Sampling::default(), 5), Some(Vec3::ZERO));
+    }
+
+    #[test]
+    fn sample_clamps_and_loops() {
+        let dense = track(TRACK_HALF_TRANSLATION, false, &[(0, Vec3::ZERO), (1, Vec3::X)]);
+        let clamp = sampling(None, Wrap::Clamp);
+        assert_eq!(dense.sample(-1.0, &clamp, 2), Some(Vec3::ZERO));
+        assert_eq!(dense.sample(5.0, &clamp, 2), Some(Vec3::X));
+        // A looped dense track blends its last key back into its first.
+        let looped = sampling(None, Wrap::Loop);
+        assert_eq!(dense.sample(1.5, &looped, 2), Some(vec3(0.5, 0.0, 0.0)));
+        assert_eq!(dense.sample(2.0, &looped, 2), Some(Vec3::ZERO));
+        assert_eq!(dense.sample(-0.5, &looped, 2), Some(vec3(0.5, 0.0, 0.0)));
+
+        let sparse = track(TRACK_HALF_TRANSLATION, true, &[(2, Vec3::ZERO), (6, vec3(6.0, 0.0, 0.0))]);
+        assert_eq!(sparse.sample(1.0, &clamp, 10), None);
+        assert_eq!(sparse.sample(2.0, &clamp, 10), Some(Vec3::ZERO));
+        assert_eq!(sparse.sample(9.0, &clamp, 10), Some(vec3(6.0, 0.0, 0.0)));
+        // Looping, the time before the first key runs from frame 6 to 12.
+        assert_eq!(sparse.sample(0.0, &looped, 10), Some(vec3(6.0, 0.0, 0.0)));
+        let linear_loop = sampling(Some(Interpolation::Linear), Wrap::Loop);
+        for (time, x) in [(0.0, 2.0), (8.0, 4.0), (10.0, 2.0)] {
+            let value = sparse.sample(time, &linear_loop, 10).unwrap();
+            assert!(value.abs_diff_eq(vec3(x, 0.0, 0.0), 1e-5), "{}: {:?}", time, value);
+        }
+
+        let empty: Track<Vec3> = track(TRACK_HALF_TRANSLATION, false, &[]);
+        assert_eq!(empty.sample(0.0, &looped, 10), None);
+    }
+
+    #[test]
+    fn frame_times_resample() {
+        let times = |fps, wrap| Sampling { interpolation: None, wrap, fps }.frame_times(10);
+        assert_eq!(times(30.0, Wrap::Clamp), (0..10).map(|f| f as f32).collect::<Vec<_>>());
+        let clamp_60 = times(60.0, Wrap::Clamp);
+        assert_eq!((clamp_60.len(), clamp_60[1], *clamp_60.last().unwrap()), (19, 0.5, 9.0));
+        let loop_60 = times(60.0, Wrap::Loop);
+        assert_eq!((loop_60.len(), *loop_60.last().unwrap()), (20, 9.5));
+        assert_eq!(times(15.0, Wrap::Clamp), [0.0, 2.0, 4.0, 6.0, 8.0]);
+        assert_eq!(times(30.0, Wrap::Loop).len(), 10);
+        assert_eq!(Sampling::default().frame_times(1), [0.0]);
+    }
+
+    /// Checks that every key `kept` drops is rebuilt within `tolerance` by
+    /// the kept keys around it.
+    fn check_reduction<T: Interpolate>(times: &[f32], values: &[T], interpolation: Interpolation, tolerance: &Tolerance, kept: &[usize]) {
+        assert_eq!(kept.first(), Some(&0));
+        assert_eq!(kept.last(), Some(&(values.len() - 1)));
+        for pair in kept.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            assert!(a < b);
+            for i in a + 1..b {
+                let t = (times[i] - times[a]) / (times[b] - times[a]);
+                assert!(T::within(interpolation.blend(values[a], values[b], t), values[i], tolerance), "key {} between {} and {}", i, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn reduce_keys_keeps_endpoints() {
+        let tolerance = Tolerance { angle: 1e-3, distance: 1e-3 };
+        assert!(reduce_keys::<Vec3>(&[], &[], Interpolation::Linear, &tolerance).is_empty());
+        assert_eq!(reduce_keys(&[0.0], &[Vec3::ONE], Interpolation::Linear, &tolerance), [0]);
+
+        let times: Vec<f32> = (0..100).map(|f| f as f32).collect();
+        let line: Vec<Vec3> = times.iter().map(|&t| vec3(t, 2.0 * t, -t) * 0.1).collect();
+        assert_eq!(reduce_keys(&times, &line, Interpolation::Linear, &tolerance), [0, 99]);
+        let still = vec![Vec3::ONE; 100];
+        assert_eq!(reduce_keys(&times, &still, Interpolation::Step, &tolerance), [0, 99]);
+        let spin: Vec<Quat> = times.iter().map(|&t| Quat::from_rotation_y(t * 0.02)).collect();
+        assert_eq!(reduce_keys(&times, &spin, Interpolation::Slerp, &tolerance), [0, 99]);
+        // Nothing fits, so nothing goes.
+        assert_eq!(reduce_keys_by(5, |_, _, _| false), [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn reduce_keys_stays_within_tolerance() {
+        let times: Vec<f32> = (0..2000).map(|f| f as f32 * 0.5).collect();
+        let wave: Vec<Vec3> = times.iter().map(|&t| vec3((t * 0.05).sin(), (t * 0.013).cos() * 3.0, (t * 0.2).sin() * 0.01)).collect();
+        let turn: Vec<Quat> = times.iter()
+            .map(|&t| Quat::from_euler(EulerRot::YXZ, (t * 0.03).sin(), t * 0.001, (t * 0.11).cos() * 0.2))
+            .collect();
+        for tolerance in [Tolerance { angle: 1e-4, distance: 1e-4 }, Tolerance { angle: 0.01, distance: 0.01 }] {
+            for interpolation in [Interpolation::Step, Interpolation::Linear, Interpolation::Slerp] {
+                let kept = reduce_keys(&times, &wave, interpolation, &tolerance);
+                check_reduction(&times, &wave, interpolation, &tolerance, &kept);
+                let kept = reduce_keys(&times, &turn, interpolation, &tolerance);
+                check_reduction(&times, &turn, interpolation, &tolerance, &kept);
+                if interpolation != Interpolation::Step && tolerance.angle >= 0.01 {
+                    assert!(kept.len() < times.len() / 4, "{:?}: kept {}", interpolation, kept.len());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn camera_read() {
+        fn pad(data: &mut Vec<u8>) {
+            data.resize(data.len().next_multiple_of(16), 0xcd);
+        }
+        fn header(data: &mut Vec<u8>, kind: u16, interpolation: u16, count: u16, unknown: u32) {
+            for v in [kind, interpolation, count, 0] {
+                data.extend(v.to_le_bytes());
+            }
+            data.extend(0u32.to_le_bytes());
+            data.extend(unknown.to_le_bytes());
+        }
+        let floats = |data: &mut Vec<u8>, v: &[f32]| v.iter().for_each(|f| data.extend(f.to_le_bytes()));
+
+        let mut data = vec![0u8; 0x38];
+        // A dense rotation track of plain quaternions, starting on a
+        // boundary of 8 so that its values need aligning.
+        header(&mut data, TRACK_ROTATION, 1, 2, 0x1234);
+        pad(&mut data);
+        floats(&mut data, &[0.0, 0.0, 0.0, 1.0]);
+        floats(&mut data, &[0.0, 0.6, 0.0, 0.8]);
+        // A sparse position track whose keys are padded to 16 bytes.
+        header(&mut data, TRACK_FLOAT_TRANSLATION, 0, 2, 7);
+        data.extend([0u16, 4].iter().flat_map(|f| f.to_le_bytes()));
+        pad(&mut data);
+        floats(&mut data, &[1.0, 2.0, 3.0, 99.0]);
+        floats(&mut data, &[-1.0, 0.5, 4.0, 99.0]);
+
+        let camera = Camera::read(data.clone()).unwrap();
+        let mut rotation = track(TRACK_ROTATION, false, &[(0, Quat::IDENTITY), (1, quat(0.0, 0.6, 0.0, 0.8))]);
+        rotation.unknown = 0x1234;
+        assert_eq!(camera.rotation, rotation);
+        let mut position = track(TRACK_FLOAT_TRANSLATION, true, &[(0, vec3(1.0, 2.0, 3.0)), (4, vec3(-1.0, 0.5, 4.0))]);
+        position.unknown = 7;
+        assert_eq!(camera.position, position);
+        assert_eq!(camera.frame_count(), 5);
+        assert_eq!(camera.position_at(3), vec3(1.0, 2.0, 3.0));
+
+        data.truncate(data.len() - 8);
+        assert!(Camera::read(data).is_err());
+    }
+}
+
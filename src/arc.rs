@@ -0,0 +1,685 @@
+use std::{io::{Cursor, Write}, path::{Path, PathBuf}};
+
+use byteorder::{WriteBytesExt, LE};
+
+use crate::error::{ByteReader, Error, Result};
+
+pub(crate) fn read_string_to_null<T: AsRef<[u8]>>(reader: &mut Cursor<T>) -> Result<String> {
+    let offset = reader.position();
+    let mut buf = Vec::new();
+    loop {
+        let c = reader.u8()?;
+        if c == 0 {
+            break;
+        }
+        buf.push(c);
+    }
+    String::from_utf8(buf).map_err(|_| Error::InvalidString { offset })
+}
+
+pub fn uncompresse(data: &[u8], raw_size: u32) -> Result<Vec<u8>> {
+    let length = data.len() as u64;
+    let raw_size = raw_size as usize;
+    let mut reader = Cursor::new(data);
+    let mut control_code: u16 = 0;
+    // `raw_size` comes from the archive, so only trust it as far as the
+    // input could plausibly expand.
+    let mut output: Vec<u8> = Vec::with_capacity(raw_size.min(data.len().saturating_mul(8)));
+    while length - reader.position() > 0 {
+        if control_code & 0x100 == 0 {
+            control_code = reader.u8()? as u16;
+            control_code |= 0xFF00;
+        }
+        if control_code & 1 != 0 {
+            output.push(reader.u8()?);
+        } else {
+            let flag = reader.u16_be()?;
+            if flag == 0 {
+                break;
+            }
+            let offset = flag >> 4;
+            let len = (flag & 0xF) + 3;
+            if offset == 0 {
+                return Err(Error::InvalidValue { offset: reader.position() - 2, what: "back-reference distance" });
+            }
+            for _ in 0..len {
+                let pos = output.len() as i32 - offset as i32;
+                if pos < 0 {
+                    output.push(0);
+                } else {
+                    output.push(output[pos as usize]);
+                }
+            }
+        }
+        if output.len() > raw_size {
+            break;
+        }
+        control_code >>= 1;
+    }
+    if output.len() != raw_size {
+        return Err(Error::SizeMismatch {
+            offset: reader.position(),
+            expected: raw_size,
+            actual: output.len(),
+        });
+    }
+    Ok(output)
+}
+
+const LZ_MIN_LEN: usize = 3;
+const LZ_MAX_LEN: usize = 0xF + LZ_MIN_LEN;
+const LZ_MAX_OFFSET: usize = 0xFFF;
+const LZ_HASH_BITS: u32 = 15;
+const NIL: usize = usize::MAX;
+
+/// Hash chains over 3-byte prefixes, looking back at most `LZ_MAX_OFFSET`.
+struct MatchFinder<'a> {
+    data: &'a [u8],
+    head: Vec<usize>,
+    prev: Vec<usize>,
+    max_chain: usize,
+}
+
+impl<'a> MatchFinder<'a> {
+    fn new(data: &'a [u8], max_chain: usize) -> Self {
+        Self {
+            data,
+            head: vec![NIL; 1 << LZ_HASH_BITS],
+            prev: vec![NIL; data.len()],
+            max_chain,
+        }
+    }
+
+    fn hash(&self, pos: usize) -> usize {
+        let v = (self.data[pos] as u32) << 16 | (self.data[pos + 1] as u32) << 8 | self.data[pos + 2] as u32;
+        (v.wrapping_mul(0x9E3779B1) >> (32 - LZ_HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, pos: usize) {
+        if pos + LZ_MIN_LEN > self.data.len() {
+            return;
+        }
+        let h = self.hash(pos);
+        self.prev[pos] = self.head[h];
+        self.head[h] = pos;
+    }
+
+    /// Returns `(len, offset)` of the longest match at `pos`, or a length
+    /// below `LZ_MIN_LEN` if there is none.
+    fn find(&self, pos: usize) -> (usize, usize) {
+        if pos + LZ_MIN_LEN > self.data.len() {
+            return (0, 0);
+        }
+        let max_len = LZ_MAX_LEN.min(self.data.len() - pos);
+        let mut best = (0, 0);
+        let mut cand = self.head[self.hash(pos)];
+        let mut chain = 0;
+        while cand != NIL && pos - cand <= LZ_MAX_OFFSET && chain < self.max_chain {
+            let len = (0..max_len)
+                .take_while(|&i| self.data[cand + i] == self.data[pos + i])
+                .count();
+            if len > best.0 {
+                best = (len, pos - cand);
+                if len == max_len {
+                    break;
+                }
+            }
+            cand = self.prev[cand];
+            chain += 1;
+        }
+        best
+    }
+}
+
+/// Packs tokens behind control bytes the way `uncompresse` reads them:
+/// eight flags per byte, LSB first, 1 for a literal and 0 for a reference.
+struct LzWriter {
+    output: Vec<u8>,
+    control_pos: usize,
+    bit: u32,
+}
+
+impl LzWriter {
+    fn new(capacity: usize) -> Self {
+        Self {
+            output: Vec::with_capacity(capacity),
+            control_pos: 0,
+            bit: 8,
+        }
+    }
+
+    fn push_flag(&mut self, literal: bool) {
+        if self.bit == 8 {
+            self.control_pos = self.output.len();
+            self.output.push(0);
+            self.bit = 0;
+        }
+        if literal {
+            self.output[self.control_pos] |= 1 << self.bit;
+        }
+        self.bit += 1;
+    }
+
+    fn literal(&mut self, c: u8) {
+        self.push_flag(true);
+        self.output.push(c);
+    }
+
+    fn reference(&mut self, offset: usize, len: usize) {
+        self.push_flag(false);
+        let flag = (offset << 4 | (len - LZ_MIN_LEN)) as u16;
+        self.output.extend_from_slice(&flag.to_be_bytes());
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.push_flag(false);
+        self.output.extend_from_slice(&[0, 0]);
+        self.output
+    }
+}
+
+/// Greedy compressor: takes the longest match found within a short hash
+/// chain at every position. Fast, but not the smallest output.
+pub fn compress_fast(data: &[u8]) -> Vec<u8> {
+    let mut finder = MatchFinder::new(data, 32);
+    let mut writer = LzWriter::new(data.len() + data.len() / 8 + 3);
+    let mut pos = 0;
+    while pos < data.len() {
+        let (len, offset) = finder.find(pos);
+        if len >= LZ_MIN_LEN {
+            writer.reference(offset, len);
+            for p in pos..pos + len {
+                finder.insert(p);
+            }
+            pos += len;
+        } else {
+            writer.literal(data[pos]);
+            finder.insert(pos);
+            pos += 1;
+        }
+    }
+    writer.finish()
+}
+
+/// Optimal-parse compressor: finds the longest match at every position and
+/// picks the token sequence with the fewest bits (9 per literal, 17 per
+/// reference). Output is never larger than `compress_fast`.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut finder = MatchFinder::new(data, usize::MAX);
+    let mut matches = Vec::with_capacity(data.len());
+    for pos in 0..data.len() {
+        matches.push(finder.find(pos));
+        finder.insert(pos);
+    }
+
+    // cost[i] is the bit count of the cheapest encoding of data[i..].
+    let mut cost = vec![0usize; data.len() + 1];
+    let mut step = vec![1usize; data.len()];
+    for pos in (0..data.len()).rev() {
+        cost[pos] = cost[pos + 1] + 9;
+        let (max_len, _) = matches[pos];
+        for len in LZ_MIN_LEN..=max_len {
+            let c = cost[pos + len] + 17;
+            if c < cost[pos] {
+                cost[pos] = c;
+                step[pos] = len;
+            }
+        }
+    }
+
+    let mut writer = LzWriter::new(cost[0] / 8 + 4);
+    let mut pos = 0;
+    while pos < data.len() {
+        let len = step[pos];
+        if len >= LZ_MIN_LEN {
+            writer.reference(matches[pos].1, len);
+        } else {
+            writer.literal(data[pos]);
+        }
+        pos += len;
+    }
+    writer.finish()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ArcHeader {
+    pub magic: u32,
+    pub version: u32,
+    pub file_count: u32,
+    pub reserved: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct ArcEntry {
+    pub name: String,
+    pub name_offset: u32,
+    pub offset: u32,
+    pub size: u32,
+    pub zsize: u32,
+}
+
+impl ArcEntry {
+    /// Entries whose stored size equals the raw size are kept uncompressed.
+    pub fn is_compressed(&self) -> bool {
+        self.size != self.zsize
+    }
+}
+
+pub struct ArcArchive {
+    pub header: ArcHeader,
+    entries: Vec<ArcEntry>,
+    content: Vec<u8>,
+}
+
+impl ArcArchive {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_bytes(std::fs::read(path)?)
+    }
+
+    pub fn from_bytes(content: Vec<u8>) -> Result<Self> {
+        let mut reader = Cursor::new(&content);
+        let header = ArcHeader {
+            magic: reader.u32()?,
+            version: reader.u32()?,
+            file_count: reader.u32()?,
+            reserved: reader.u32()?,
+        };
+        if header.file_count as u64 * 16 > content.len() as u64 {
+            return Err(Error::Truncated { offset: 16 });
+        }
+        let mut entries = Vec::with_capacity(header.file_count as _);
+        for _ in 0..header.file_count {
+            entries.push(ArcEntry {
+                name: String::new(),
+                name_offset: reader.u32()?,
+                offset: reader.u32()?,
+                size: reader.u32()?,
+                zsize: reader.u32()?,
+            });
+        }
+        for (i, entry) in entries.iter_mut().enumerate() {
+            // Report a bad name offset against its table row.
+            reader.set_position(16 + 16 * i as u64);
+            reader.seek_to(entry.name_offset as _)?;
+            entry.name = read_string_to_null(&mut reader)?;
+        }
+        Ok(Self {
+            header,
+            entries,
+            content,
+        })
+    }
+
+    pub fn entries(&self) -> std::slice::Iter<'_, ArcEntry> {
+        self.entries.iter()
+    }
+
+    pub fn entry(&self, name: &str) -> Option<&ArcEntry> {
+        self.entries.iter().find(|e| e.name == name)
+    }
+
+    /// Returns the decompressed bytes of `entry`. Decompression errors carry
+    /// offsets relative to the start of the entry's data.
+    pub fn read(&self, entry: &ArcEntry) -> Result<Vec<u8>> {
+        let start = entry.offset as usize;
+        let end = start + entry.zsize as usize;
+        let compressed = self.content.get(start..end).ok_or(Error::OffsetOutOfRange {
+            offset: entry.offset as _,
+            target: end as _,
+        })?;
+        if entry.is_compressed() {
+            uncompresse(compressed, entry.size)
+        } else {
+            Ok(compressed.to_vec())
+        }
+    }
+
+    pub fn read_entry(&self, name: &str) -> Result<Vec<u8>> {
+        let entry = self.entry(name).ok_or_else(|| Error::EntryNotFound(name.to_string()))?;
+        self.read(entry)
+    }
+
+    /// Writes every entry below `out_dir`, sanitising names with
+    /// `sanitize_entry_name` so nothing lands outside of it. An entry whose
+    /// path an earlier entry already took is skipped rather than
+    /// overwriting it.
+    pub fn extract_to<P: AsRef<Path>>(&self, out_dir: P) -> Result<ExtractReport> {
+        let out_dir = out_dir.as_ref();
+        let mut report = ExtractReport::default();
+        let mut used = std::collections::HashSet::new();
+        for entry in &self.entries {
+            let Some(rel_path) = sanitize_entry_name(&entry.name) else {
+                report.rejected.push(entry.name.clone());
+                continue;
+            };
+            if !used.insert(rel_path.clone()) {
+                report.collisions.push((entry.name.clone(), rel_path));
+                continue;
+            }
+            let path = out_dir.join(&rel_path);
+            if let Some(dir_path) = path.parent() {
+                std::fs::create_dir_all(dir_path)?;
+            }
+            std::fs::write(&path, self.read(entry)?)?;
+            let same = rel_path.iter().map(|c| c.to_string_lossy()).collect::<Vec<_>>().join("/") == entry.name;
+            if !same {
+                report.renamed.push((entry.name.clone(), rel_path));
+            }
+            report.written.push(path);
+        }
+        Ok(report)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ExtractReport {
+    pub written: Vec<PathBuf>,
+    /// Entries whose stored name was rewritten, with the relative path used.
+    pub renamed: Vec<(String, PathBuf)>,
+    /// Entries with nothing left to write to after sanitising.
+    pub rejected: Vec<String>,
+    /// Entries not written because they sanitise to the same relative path
+    /// as an earlier entry.
+    pub collisions: Vec<(String, PathBuf)>,
+}
+
+/// Turns an entry name into a relative path that cannot escape the output
+/// directory: both `/` and `\` separate components, a drive prefix like
+/// `C:` and leading separators are dropped, any other `:` becomes `_` so
+/// no component names a drive or stream, and `.`/`..` components are
+/// removed. Returns `None` when no component remains.
+pub fn sanitize_entry_name(name: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for (i, component) in name.split(['/', '\\']).enumerate() {
+        let bytes = component.as_bytes();
+        let component = if i == 0 && bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' {
+            &component[2..]
+        } else {
+            component
+        };
+        let component = component.replace(':', "_");
+        if component.is_empty() || component == "." || component == ".." {
+            continue;
+        }
+        path.push(&component);
+    }
+    if path.as_os_str().is_empty() {
+        None
+    } else {
+        Some(path)
+    }
+}
+
+fn align_to(v: usize, a: usize) -> usize {
+    v.div_ceil(a) * a
+}
+
+/// Builds a new `.arc` from named buffers. Entries are compressed unless
+/// that would not make them smaller, in which case they are stored raw with
+/// `size == zsize`.
+pub struct ArcWriter {
+    pub magic: u32,
+    pub version: u32,
+    pub reserved: u32,
+    /// Alignment of the data section and of every entry within it.
+    pub alignment: usize,
+    /// Use `compress_fast` instead of the optimal-parse `compress`.
+    pub fast: bool,
+    files: Vec<(String, Vec<u8>)>,
+}
+
+impl ArcWriter {
+    pub fn new(header: &ArcHeader) -> Self {
+        Self {
+            magic: header.magic,
+            version: header.version,
+            reserved: header.reserved,
+            alignment: 16,
+            fast: false,
+            files: Vec::new(),
+        }
+    }
+
+    /// Starts from the header and decompressed entries of an existing archive.
+    pub fn from_archive(archive: &ArcArchive) -> Result<Self> {
+        let mut writer = Self::new(&archive.header);
+        for entry in archive.entries() {
+            writer.add(entry.name.clone(), archive.read(entry)?);
+        }
+        Ok(writer)
+    }
+
+    /// Adds an entry, replacing any existing entry with the same name.
+    pub fn add(&mut self, name: impl Into<String>, data: Vec<u8>) {
+        let name = name.into();
+        if let Some(f) = self.files.iter_mut().find(|f| f.0 == name) {
+            f.1 = data;
+        } else {
+            self.files.push((name, data));
+        }
+    }
+
+    pub fn write(&self) -> Vec<u8> {
+        let names_start = 16 + 16 * self.files.len();
+        let mut name_offsets = Vec::with_capacity(self.files.len());
+        let mut names_end = names_start;
+        for (name, _) in &self.files {
+            name_offsets.push(names_end);
+            names_end += name.len() + 1;
+        }
+
+        let mut data_offsets = Vec::with_capacity(self.files.len());
+        let mut blobs = Vec::with_capacity(self.files.len());
+        let mut data_end = align_to(names_end, self.alignment);
+        for (_, data) in &self.files {
+            let compressed = if self.fast { compress_fast(data) } else { compress(data) };
+            let blob = if compressed.len() < data.len() { compressed } else { data.clone() };
+            data_offsets.push(data_end);
+            data_end = align_to(data_end + blob.len(), self.alignment);
+            blobs.push(blob);
+        }
+
+        let mut file = Cursor::new(Vec::with_capacity(data_end));
+        file.write_u32::<LE>(self.magic).unwrap();
+        file.write_u32::<LE>(self.version).unwrap();
+        file.write_u32::<LE>(self.files.len() as _).unwrap();
+        file.write_u32::<LE>(self.reserved).unwrap();
+        for (i, (_, data)) in self.files.iter().enumerate() {
+            file.write_u32::<LE>(name_offsets[i] as _).unwrap();
+            file.write_u32::<LE>(data_offsets[i] as _).unwrap();
+            file.write_u32::<LE>(data.len() as _).unwrap();
+            file.write_u32::<LE>(blobs[i].len() as _).unwrap();
+        }
+        for (name, _) in &self.files {
+            file.write_all(name.as_bytes()).unwrap();
+            file.write_u8(0).unwrap();
+        }
+        for (i, blob) in blobs.iter().enumerate() {
+            file.set_position(data_offsets[i] as _);
+            file.write_all(blob).unwrap();
+        }
+        let mut content = file.into_inner();
+        content.resize(data_end, 0);
+        content
+    }
+
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        Ok(std::fs::write(path, self.write())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic bytes that no LZ match helps with.
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 56) as u8
+        }).collect()
+    }
+
+    /// Checks that both compressors round-trip `data` and that `compress`
+    /// is no larger than `compress_fast`.
+    fn check_compressors(data: &[u8]) {
+        let fast = compress_fast(data);
+        let best = compress(data);
+        assert_eq!(uncompresse(&fast, data.len() as u32).unwrap(), data);
+        assert_eq!(uncompresse(&best, data.len() as u32).unwrap(), data);
+        assert!(best.len() <= fast.len(), "{} > {}", best.len(), fast.len());
+    }
+
+    #[test]
+    fn compress_round_trips() {
+        check_compressors(&[]);
+        check_compressors(b"a");
+        check_compressors(b"abc");
+        check_compressors(&noise(10_000, 2));
+        check_compressors(&vec![0x55; 10_000]);
+        check_compressors(&b"0123456789".repeat(1000));
+        let mut mixed = noise(3000, 3);
+        mixed.extend(b"repeat me ".repeat(50));
+        mixed.extend(noise(3000, 4));
+        check_compressors(&mixed);
+    }
+
+    #[test]
+    fn uncompresse_stops_past_raw_size() {
+        let data = vec![1u8; 1000];
+        let compressed = compress(&data);
+        assert!(matches!(uncompresse(&compressed, 10), Err(Error::SizeMismatch { expected: 10, actual, .. }) if actual <= 10 + LZ_MAX_LEN));
+        assert!(matches!(uncompresse(&compressed, 1001), Err(Error::SizeMismatch { actual: 1000, .. })));
+        // A huge claimed size is an error, not an allocation failure.
+        assert!(matches!(uncompresse(&compressed, u32::MAX), Err(Error::SizeMismatch { .. })));
+    }
+
+    #[test]
+    fn compress_shrinks_repetitive_input() {
+        let data = vec![7u8; 10_000];
+        // Each 18-byte reference costs 17 bits.
+        assert!(compress(&data).len() < data.len() / 8);
+    }
+
+    #[test]
+    fn compress_overlapping_matches() {
+        // Runs shorter than the maximum match length copy from themselves.
+        for period in 1..=LZ_MAX_LEN + 2 {
+            let data: Vec<u8> = noise(period, period as u64).into_iter().cycle().take(500).collect();
+            check_compressors(&data);
+            assert!(compress(&data).len() < data.len());
+        }
+    }
+
+    #[test]
+    fn compress_distances_beyond_window() {
+        // The only repeat is further back than a reference can reach.
+        let block = noise(200, 5);
+        for gap in [LZ_MAX_OFFSET - 200, LZ_MAX_OFFSET - 199, LZ_MAX_OFFSET, 5000] {
+            let mut data = block.clone();
+            data.extend(noise(gap, gap as u64));
+            data.extend(&block);
+            check_compressors(&data);
+        }
+    }
+
+    #[test]
+    fn compress_terminator() {
+        // A terminator is a reference flag followed by 0x0000, so it may
+        // need a control byte of its own when the last one is full.
+        for len in 0..40 {
+            let data = noise(len, 6);
+            for compressed in [compress(&data), compress_fast(&data)] {
+                // `len` literals plus the terminator, eight flags per byte.
+                assert_eq!(compressed.len(), (len + 1).div_ceil(8) + len + 2, "len {}", len);
+                assert!(compressed.ends_with(&[0, 0]));
+                let control = compressed[len / 8 * 9];
+                assert_eq!(control >> (len % 8), 0, "len {}", len);
+                // Anything after the terminator is ignored.
+                let mut padded = compressed.clone();
+                padded.extend([0xFF; 4]);
+                assert_eq!(uncompresse(&padded, len as u32).unwrap(), data);
+            }
+        }
+    }
+
+    fn header() -> ArcHeader {
+        ArcHeader { magic: 0x00435241, version: 1, file_count: 0, reserved: 7 }
+    }
+
+    #[test]
+    fn writer_round_trips_through_reader() {
+        let files = vec![
+            ("a.model".to_string(), b"abcabcabcabcabc hello hello hello".repeat(20)),
+            ("dir/empty".to_string(), Vec::new()),
+            ("noise.bin".to_string(), noise(1000, 1)),
+            ("x".to_string(), vec![0; 5000]),
+        ];
+        let mut writer = ArcWriter::new(&header());
+        for (name, data) in &files {
+            writer.add(name.clone(), data.clone());
+        }
+        let bytes = writer.write();
+        let archive = ArcArchive::from_bytes(bytes.clone()).unwrap();
+        assert_eq!((archive.header.magic, archive.header.version, archive.header.reserved), (0x00435241, 1, 7));
+        assert_eq!(archive.header.file_count as usize, files.len());
+        for ((name, data), entry) in files.iter().zip(archive.entries()) {
+            assert_eq!(&entry.name, name);
+            assert_eq!(&archive.read(entry).unwrap(), data);
+            assert_eq!(entry.offset % 16, 0);
+        }
+
+        let empty = archive.entry("dir/empty").unwrap();
+        assert_eq!((empty.size, empty.zsize), (0, 0));
+        let noise = archive.entry("noise.bin").unwrap();
+        assert_eq!(noise.size, noise.zsize);
+        assert!(!noise.is_compressed());
+        let zeros = archive.entry("x").unwrap();
+        assert!(zeros.is_compressed() && zeros.zsize < zeros.size);
+
+        let rewritten = ArcWriter::from_archive(&archive).unwrap().write();
+        assert_eq!(rewritten, bytes);
+    }
+
+    #[test]
+    fn sanitize_drops_escapes() {
+        let path = |name| sanitize_entry_name(name).map(|p| p.iter().map(|c| c.to_string_lossy().into_owned()).collect::<Vec<_>>());
+        assert_eq!(path("a/b\\c.model"), Some(vec!["a".into(), "b".into(), "c.model".into()]));
+        assert_eq!(path("C:\\x/../y"), Some(vec!["x".into(), "y".into()]));
+        assert_eq!(path("/abs/./p"), Some(vec!["abs".into(), "p".into()]));
+        assert_eq!(path("a/C:x"), Some(vec!["a".into(), "C_x".into()]));
+        assert_eq!(path("C:D:e"), Some(vec!["D_e".into()]));
+        assert_eq!(path("../.."), None);
+    }
+
+    #[test]
+    fn extract_reports_collisions() {
+        let mut writer = ArcWriter::new(&header());
+        writer.add("d/a", vec![1]);
+        writer.add("d\\a", vec![2]);
+        writer.add("../d/a", vec![3]);
+        writer.add("b", vec![4]);
+        let archive = ArcArchive::from_bytes(writer.write()).unwrap();
+        let dir = std::env::temp_dir().join(format!("fuck_dance_extract_{}", std::process::id()));
+        let report = archive.extract_to(&dir).unwrap();
+        let content = std::fs::read(dir.join("d").join("a")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(content, [1]);
+        assert_eq!(report.written.len(), 2);
+        let collided: Vec<&str> = report.collisions.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(collided, ["d\\a", "../d/a"]);
+    }
+
+    #[test]
+    fn writer_replaces_entries_by_name() {
+        let mut writer = ArcWriter::new(&header());
+        writer.add("a", vec![1]);
+        writer.add("b", vec![2]);
+        writer.add("a", vec![3]);
+        let archive = ArcArchive::from_bytes(writer.write()).unwrap();
+        let names: Vec<&str> = archive.entries().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["a", "b"]);
+        assert_eq!(archive.read_entry("a").unwrap(), [3]);
+    }
+}
@@ -0,0 +1,222 @@
+use std::{fmt::Write as _, path::{Path, PathBuf}, sync::{atomic::{AtomicUsize, Ordering}, Mutex}};
+
+use crate::arc::{sanitize_entry_name, ArcArchive, ArcEntry};
+use crate::error::Result;
+use crate::ktmdl;
+
+#[derive(Debug, Clone)]
+pub enum BatchStatus {
+    Converted(PathBuf),
+    Skipped(String),
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct BatchItem {
+    pub archive: PathBuf,
+    /// The `.model` entry, or empty when the whole archive was skipped or failed.
+    pub entry: String,
+    pub status: BatchStatus,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BatchReport {
+    pub items: Vec<BatchItem>,
+}
+
+impl BatchReport {
+    pub fn converted(&self) -> usize {
+        self.items.iter().filter(|i| matches!(i.status, BatchStatus::Converted(_))).count()
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.items.iter().filter(|i| matches!(i.status, BatchStatus::Skipped(_))).count()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.items.iter().filter(|i| matches!(i.status, BatchStatus::Failed(_))).count()
+    }
+
+    /// One tab-separated line per item followed by the totals.
+    pub fn summary(&self) -> String {
+        let mut out = String::new();
+        for item in &self.items {
+            let (status, detail) = match &item.status {
+                BatchStatus::Converted(path) => ("ok", path.display().to_string()),
+                BatchStatus::Skipped(reason) => ("skip", reason.clone()),
+                BatchStatus::Failed(error) => ("fail", error.clone()),
+            };
+            writeln!(out, "{}\t{}\t{}\t{}", status, item.archive.display(), item.entry, detail).unwrap();
+        }
+        writeln!(out, "converted: {}, skipped: {}, failed: {}", self.converted(), self.skipped(), self.failed()).unwrap();
+        out
+    }
+}
+
+/// Recursively collects every `.arc` file below `dir`, sorted by path.
+/// Symlinked directories are not followed, so a link cannot make it loop.
+pub fn find_archives(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                pending.push(path);
+            } else if path.extension().is_some_and(|e| e.eq_ignore_ascii_case("arc")) {
+                found.push(path);
+            }
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+/// Picks the `.b2it` for `model`: the entry with the same name up to the
+/// extension, or the archive's only `.b2it` if there is exactly one.
+fn matching_b2it<'a>(archive: &'a ArcArchive, model: &ArcEntry) -> Option<&'a ArcEntry> {
+    let stem = model.name.strip_suffix(".model")?;
+    let b2it_name = format!("{}.b2it", stem);
+    if let Some(entry) = archive.entry(&b2it_name) {
+        return Some(entry);
+    }
+    let mut b2its = archive.entries().filter(|e| e.name.ends_with(".b2it"));
+    match (b2its.next(), b2its.next()) {
+        (Some(entry), None) => Some(entry),
+        _ => None,
+    }
+}
+
+fn convert_model(archive: &ArcArchive, model: &ArcEntry, b2it: &ArcEntry, save_path: &Path) -> std::result::Result<PathBuf, String> {
+    let content = archive.read(model).map_err(|e| e.to_string())?;
+    let b2it_content = archive.read(b2it).map_err(|e| format!("{}: {}", b2it.name, e))?;
+    let bone_names = ktmdl::parse_b2it(&b2it_content).map_err(|e| format!("{}: {}", b2it.name, e))?;
+    if let Some(dir_path) = save_path.parent() {
+        std::fs::create_dir_all(dir_path).map_err(|e| e.to_string())?;
+    }
+    let save_path = save_path.to_str().ok_or("output path is not valid UTF-8")?;
+    ktmdl::ktmodel_to_pmx(content, bone_names, save_path, false, &Default::default()).map_err(|e| e.to_string())?;
+    Ok(PathBuf::from(format!("{}.pmx", save_path)))
+}
+
+fn convert_archive(path: &Path, out_dir: &Path) -> Vec<BatchItem> {
+    let item = |entry: &str, status| BatchItem {
+        archive: path.to_path_buf(),
+        entry: entry.to_string(),
+        status,
+    };
+    let archive = match ArcArchive::open(path) {
+        Ok(archive) => archive,
+        Err(e) => return vec![item("", BatchStatus::Failed(e.to_string()))],
+    };
+    let models: Vec<_> = archive.entries().filter(|e| e.name.ends_with(".model")).collect();
+    if models.is_empty() {
+        return vec![item("", BatchStatus::Skipped("no .model entry".to_string()))];
+    }
+
+    let mut items = Vec::new();
+    for model in models {
+        let Some(b2it) = matching_b2it(&archive, model) else {
+            items.push(item(&model.name, BatchStatus::Skipped("no matching .b2it entry".to_string())));
+            continue;
+        };
+        let Some(rel_path) = sanitize_entry_name(&model.name) else {
+            items.push(item(&model.name, BatchStatus::Skipped("invalid entry name".to_string())));
+            continue;
+        };
+        let result = convert_model(&archive, model, b2it, &out_dir.join(rel_path));
+        items.push(item(&model.name, match result {
+            Ok(path) => BatchStatus::Converted(path),
+            Err(e) => BatchStatus::Failed(e),
+        }));
+    }
+    items
+}
+
+/// Converts every `.model` in every `.arc` below `in_dir` on `jobs` threads.
+/// Output for `a/b.arc` goes to `out_dir/a/b/<entry>.pmx`, so archives with
+/// the same entry names do not overwrite each other.
+pub fn convert_dir(in_dir: &Path, out_dir: &Path, jobs: usize) -> Result<BatchReport> {
+    let archives = find_archives(in_dir)?;
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::new());
+    std::thread::scope(|s| {
+        for _ in 0..jobs.max(1).min(archives.len()) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(path) = archives.get(i) else {
+                    break;
+                };
+                let rel_path = path.strip_prefix(in_dir).unwrap_or(path).with_extension("");
+                let items = convert_archive(path, &out_dir.join(rel_path));
+                results.lock().unwrap().push((i, items));
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|r| r.0);
+    Ok(BatchReport {
+        items: results.into_iter().flat_map(|r| r.1).collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arc::{ArcHeader, ArcWriter};
+
+    fn write_arc(path: &Path, files: &[&str]) {
+        let mut writer = ArcWriter::new(&ArcHeader { magic: 0x00435241, version: 1, file_count: 0, reserved: 0 });
+        for name in files {
+            // Not a valid `.model` or `.b2it`, so conversion fails on the
+            // `.b2it` and the error names the one that was picked.
+            writer.add(*name, b"x".to_vec());
+        }
+        writer.write_to_file(path).unwrap();
+    }
+
+    #[test]
+    fn convert_dir_report() {
+        let root = std::env::temp_dir().join(format!("fuck_dance_batch_{}", std::process::id()));
+        let (in_dir, out_dir) = (root.join("in"), root.join("out"));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(in_dir.join("sub")).unwrap();
+        write_arc(&in_dir.join("a.arc"), &["chr/a.model", "chr/b.b2it", "chr/a.b2it", "chr/b.model", "chr/c.model"]);
+        write_arc(&in_dir.join("sub").join("only.arc"), &["x.model", "bones.b2it"]);
+        write_arc(&in_dir.join("sub").join("empty.arc"), &["readme"]);
+        std::fs::write(in_dir.join("corrupt.ARC"), b"not an archive").unwrap();
+        // A link back up the tree, which is not followed.
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&in_dir, in_dir.join("sub").join("loop")).unwrap();
+
+        let reports: Vec<BatchReport> = [1, 4].iter().map(|&jobs| convert_dir(&in_dir, &out_dir, jobs).unwrap()).collect();
+        std::fs::remove_dir_all(&root).unwrap();
+        // Threads finish in any order, but the report follows the sorted paths.
+        assert_eq!(reports[0].summary(), reports[1].summary());
+
+        let items: Vec<(PathBuf, &str, &BatchStatus)> = reports[0].items.iter()
+            .map(|i| (i.archive.strip_prefix(&in_dir).unwrap().to_path_buf(), i.entry.as_str(), &i.status))
+            .collect();
+        let failed_on = |status: &BatchStatus, b2it: &str| matches!(status, BatchStatus::Failed(e) if e.starts_with(&format!("{}:", b2it)));
+        let skipped = |status: &BatchStatus, reason: &str| matches!(status, BatchStatus::Skipped(r) if r == reason);
+        assert_eq!(items.len(), 6, "{}", reports[0].summary());
+        // Pairing by name, whatever the entry order.
+        assert_eq!((items[0].0.as_path(), items[0].1), (Path::new("a.arc"), "chr/a.model"));
+        assert!(failed_on(items[0].2, "chr/a.b2it"));
+        assert_eq!(items[1].1, "chr/b.model");
+        assert!(failed_on(items[1].2, "chr/b.b2it"));
+        // Several `.b2it` and none named after the model.
+        assert_eq!(items[2].1, "chr/c.model");
+        assert!(skipped(items[2].2, "no matching .b2it entry"));
+        assert_eq!((items[3].0.as_path(), items[3].1), (Path::new("corrupt.ARC"), ""));
+        assert!(matches!(items[3].2, BatchStatus::Failed(_)));
+        assert_eq!(items[4].0, Path::new("sub").join("empty.arc"));
+        assert!(skipped(items[4].2, "no .model entry"));
+        // The only `.b2it` in the archive, whatever its name.
+        assert_eq!((items[5].0.clone(), items[5].1), (Path::new("sub").join("only.arc"), "x.model"));
+        assert!(failed_on(items[5].2, "bones.b2it"));
+        assert_eq!((reports[0].converted(), reports[0].skipped(), reports[0].failed()), (0, 2, 4));
+    }
+}
+
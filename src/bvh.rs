@@ -0,0 +1,243 @@
+use std::fmt::Write as _;
+
+use glam::*;
+
+use crate::anm::{Animation, Sampling};
+use crate::error::{Error, Result};
+use crate::ktmdl::KTModel;
+use crate::pose::{Fallback, Pose};
+
+/// Order of the three rotation channels, outermost first: `Zxy` writes
+/// `Zrotation Xrotation Yrotation` and means `Rz * Rx * Ry`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RotationOrder {
+    Xyz,
+    Xzy,
+    Yxz,
+    Yzx,
+    #[default]
+    Zxy,
+    Zyx,
+}
+
+impl RotationOrder {
+    fn axes(self) -> [char; 3] {
+        match self {
+            RotationOrder::Xyz => ['X', 'Y', 'Z'],
+            RotationOrder::Xzy => ['X', 'Z', 'Y'],
+            RotationOrder::Yxz => ['Y', 'X', 'Z'],
+            RotationOrder::Yzx => ['Y', 'Z', 'X'],
+            RotationOrder::Zxy => ['Z', 'X', 'Y'],
+            RotationOrder::Zyx => ['Z', 'Y', 'X'],
+        }
+    }
+
+    fn euler(self) -> EulerRot {
+        match self {
+            RotationOrder::Xyz => EulerRot::XYZ,
+            RotationOrder::Xzy => EulerRot::XZY,
+            RotationOrder::Yxz => EulerRot::YXZ,
+            RotationOrder::Yzx => EulerRot::YZX,
+            RotationOrder::Zxy => EulerRot::ZXY,
+            RotationOrder::Zyx => EulerRot::ZYX,
+        }
+    }
+}
+
+impl std::str::FromStr for RotationOrder {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        match s.to_ascii_lowercase().as_str() {
+            "xyz" => Ok(RotationOrder::Xyz),
+            "xzy" => Ok(RotationOrder::Xzy),
+            "yxz" => Ok(RotationOrder::Yxz),
+            "yzx" => Ok(RotationOrder::Yzx),
+            "zxy" => Ok(RotationOrder::Zxy),
+            "zyx" => Ok(RotationOrder::Zyx),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BvhOptions {
+    pub rotation_order: RotationOrder,
+    /// Give every joint position channels, not just the root. This keeps
+    /// the bone stretching some `.anm` tracks do.
+    pub translations: bool,
+    /// Frame rate and interpolation of the MOTION section.
+    pub sampling: Sampling,
+}
+
+/// BVH names end at whitespace.
+fn joint_name(model: &KTModel, i: usize) -> String {
+    let name: Vec<&str> = model.bone_names.get(i).map(|n| n.split_whitespace().collect()).unwrap_or_default();
+    if name.is_empty() { format!("bone_{}", i) } else { name.join("_") }
+}
+
+struct Writer<'a> {
+    model: &'a KTModel,
+    options: &'a BvhOptions,
+    children: Vec<Vec<usize>>,
+    out: String,
+    /// Joints in the order their channels appear on a MOTION line.
+    order: Vec<usize>,
+}
+
+impl Writer<'_> {
+    fn joint(&mut self, i: usize, depth: usize) {
+        let indent = "\t".repeat(depth);
+        let (keyword, offset) = match self.model.bone_parent[i] {
+            Some(p) => ("JOINT", self.model.bone_pos[i] - self.model.bone_pos[p]),
+            None => ("ROOT", self.model.bone_pos[i]),
+        };
+        let [a, b, c] = self.options.rotation_order.axes();
+        let rotations = format!("{a}rotation {b}rotation {c}rotation");
+        let channels = if keyword == "ROOT" || self.options.translations {
+            format!("6 Xposition Yposition Zposition {}", rotations)
+        } else {
+            format!("3 {}", rotations)
+        };
+        writeln!(self.out, "{}{} {}", indent, keyword, joint_name(self.model, i)).unwrap();
+        writeln!(self.out, "{}{{", indent).unwrap();
+        writeln!(self.out, "{}\tOFFSET {} {} {}", indent, offset.x, offset.y, offset.z).unwrap();
+        writeln!(self.out, "{}\tCHANNELS {}", indent, channels).unwrap();
+        self.order.push(i);
+        if self.children[i].is_empty() {
+            writeln!(self.out, "{}\tEnd Site", indent).unwrap();
+            writeln!(self.out, "{}\t{{", indent).unwrap();
+            writeln!(self.out, "{}\t\tOFFSET 0 0 0", indent).unwrap();
+            writeln!(self.out, "{}\t}}", indent).unwrap();
+        }
+        for c in self.children[i].clone() {
+            self.joint(c, depth + 1);
+        }
+        writeln!(self.out, "{}}}", indent).unwrap();
+    }
+}
+
+/// Writes `anm` on the skeleton of `model` as BVH, in `.model` units and
+/// axes. Every joint rests in world orientation at its bind position, so
+/// OFFSET is the bind offset from the parent. Position channels hold the
+/// full translation from the parent, OFFSET included, and the root's is
+/// its absolute position. MOTION lines are sampled at `options.sampling`,
+/// and bones without a track hold their bind pose.
+pub fn anm_to_bvh(model: &KTModel, anm: &Animation, options: &BvhOptions) -> Result<String> {
+    let bone_count = model.bone_matrix.len();
+    let roots: Vec<usize> = (0..bone_count).filter(|&i| model.bone_parent[i].is_none()).collect();
+    if roots.len() != 1 {
+        return Err(Error::InvalidValue { offset: 0x18, what: "skeleton (BVH needs exactly one root bone)" });
+    }
+    let mut children = vec![Vec::new(); bone_count];
+    for (i, p) in model.bone_parent.iter().enumerate() {
+        if let Some(p) = *p {
+            children[p].push(i);
+        }
+    }
+
+    let mut writer = Writer { model, options, children, out: String::from("HIERARCHY\n"), order: Vec::new() };
+    writer.joint(roots[0], 0);
+    let Writer { mut out, order, .. } = writer;
+
+    let times = options.sampling.frame_times(anm.frame_count());
+    writeln!(out, "MOTION").unwrap();
+    writeln!(out, "Frames: {}", times.len()).unwrap();
+    writeln!(out, "Frame Time: {}", 1.0 / options.sampling.fps).unwrap();
+    let euler = options.rotation_order.euler();
+    for time in times {
+        let pose = Pose::sample(model, anm, time, &options.sampling, Fallback::Bind)?.parent_relative(model, Mat4::IDENTITY);
+        let mut line = Vec::with_capacity(order.len() * 6);
+        for &i in &order {
+            let (rot, pos) = pose[i];
+            if model.bone_parent[i].is_none() || options.translations {
+                line.extend(pos.to_array());
+            }
+            let (a, b, c) = rot.to_euler(euler);
+            line.extend([a, b, c].map(f32::to_degrees));
+        }
+        let line: Vec<String> = line.iter().map(|v| v.to_string()).collect();
+        writeln!(out, "{}", line.join(" ")).unwrap();
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anm::{BoneTracks, FRAME_RATE};
+
+    /// A root, a child with a name BVH cannot hold and an unnamed leaf.
+    fn model() -> KTModel {
+        let bone_pos = vec![vec3(0.0, 1.0, 0.0), vec3(1.0, 2.0, 0.0), vec3(2.0, 2.5, 0.0)];
+        KTModel {
+            bone_names: vec!["root".to_string(), "left arm".to_string()],
+            bone_matrix: bone_pos.iter().map(|&p| Mat4::from_translation(p)).collect(),
+            bone_pos,
+            bone_parent: vec![None, Some(0), Some(1)],
+            bone_unknown: vec![Default::default(); 3],
+            meshs: Vec::new(),
+        }
+    }
+
+    fn still(frame_count: u32) -> Animation {
+        Animation { max_frame: frame_count - 1, bones: vec![BoneTracks::default(); 3], header: Vec::new() }
+    }
+
+    #[test]
+    fn hierarchy_and_channels() {
+        let model = model();
+        let bvh = anm_to_bvh(&model, &still(10), &BvhOptions::default()).unwrap();
+        let expected = "\
+HIERARCHY
+ROOT root
+{
+	OFFSET 0 1 0
+	CHANNELS 6 Xposition Yposition Zposition Zrotation Xrotation Yrotation
+	JOINT left_arm
+	{
+		OFFSET 1 1 0
+		CHANNELS 3 Zrotation Xrotation Yrotation
+		JOINT bone_2
+		{
+			OFFSET 1 0.5 0
+			CHANNELS 3 Zrotation Xrotation Yrotation
+			End Site
+			{
+				OFFSET 0 0 0
+			}
+		}
+	}
+}
+MOTION
+Frames: 10
+";
+        assert!(bvh.starts_with(expected), "{}", bvh);
+        let lines: Vec<&str> = bvh.lines().collect();
+        assert_eq!(lines[22], format!("Frame Time: {}", 1.0 / FRAME_RATE));
+        assert_eq!(lines.len(), 23 + 10);
+        let values: Vec<f32> = lines[23].split(' ').map(|v| v.parse().unwrap()).collect();
+        assert_eq!(values, [0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+
+        let options = BvhOptions {
+            rotation_order: RotationOrder::Yxz,
+            translations: true,
+            sampling: Sampling { fps: 60.0, ..Default::default() },
+        };
+        let bvh = anm_to_bvh(&model, &still(10), &options).unwrap();
+        let lines: Vec<&str> = bvh.lines().collect();
+        assert_eq!(lines[8].trim(), "CHANNELS 6 Xposition Yposition Zposition Yrotation Xrotation Zrotation");
+        assert_eq!((lines[21], lines[22]), ("Frames: 19", format!("Frame Time: {}", 1.0 / 60.0f32).as_str()));
+        // Position channels hold the bind offset from the parent.
+        let values: Vec<f32> = lines[23].split(' ').map(|v| v.parse().unwrap()).collect();
+        assert_eq!(values[6..9], [1.0, 1.0, 0.0]);
+        assert_eq!(values[12..15], [1.0, 0.5, 0.0]);
+    }
+
+    #[test]
+    fn rejects_several_roots() {
+        let mut model = model();
+        model.bone_parent[2] = None;
+        assert!(anm_to_bvh(&model, &still(1), &BvhOptions::default()).is_err());
+    }
+}
@@ -0,0 +1,153 @@
+use std::{fmt, io::{self, Cursor, Read}};
+
+use byteorder::{ReadBytesExt, LE, BE};
+
+/// Errors raised while parsing archives, models and PMX files. Every
+/// parsing variant carries the byte offset at which parsing failed.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Truncated { offset: u64 },
+    BadMagic { offset: u64 },
+    OffsetOutOfRange { offset: u64, target: u64 },
+    UnsupportedFvfSize { offset: u64, size: u8 },
+    InvalidString { offset: u64 },
+    SizeMismatch { offset: u64, expected: usize, actual: usize },
+    UnknownWeightType { offset: u64, value: u8 },
+    InvalidValue { offset: u64, what: &'static str },
+    EntryNotFound(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn offset(&self) -> Option<u64> {
+        match *self {
+            Error::Io(_) | Error::EntryNotFound(_) => None,
+            Error::Truncated { offset }
+            | Error::BadMagic { offset }
+            | Error::OffsetOutOfRange { offset, .. }
+            | Error::UnsupportedFvfSize { offset, .. }
+            | Error::InvalidString { offset }
+            | Error::SizeMismatch { offset, .. }
+            | Error::UnknownWeightType { offset, .. }
+            | Error::InvalidValue { offset, .. } => Some(offset),
+        }
+    }
+
+    fn from_io(e: io::Error, offset: u64) -> Self {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            Error::Truncated { offset }
+        } else {
+            Error::Io(e)
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Truncated { offset } => write!(f, "truncated data at 0x{:X}", offset),
+            Error::BadMagic { offset } => write!(f, "bad magic at 0x{:X}", offset),
+            Error::OffsetOutOfRange { offset, target } => {
+                write!(f, "offset 0x{:X} read at 0x{:X} is out of range", target, offset)
+            },
+            Error::UnsupportedFvfSize { offset, size } => {
+                write!(f, "unsupported vertex size {} at 0x{:X}", size, offset)
+            },
+            Error::InvalidString { offset } => write!(f, "invalid string at 0x{:X}", offset),
+            Error::SizeMismatch { offset, expected, actual } => {
+                write!(f, "decompressed {} bytes instead of {} at 0x{:X}", actual, expected, offset)
+            },
+            Error::UnknownWeightType { offset, value } => {
+                write!(f, "unknown weight type {} at 0x{:X}", value, offset)
+            },
+            Error::InvalidValue { offset, what } => write!(f, "invalid {} at 0x{:X}", what, offset),
+            Error::EntryNotFound(name) => write!(f, "{}: no such entry", name),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// Little-endian reads on a cursor that report failures at the offset the
+/// read started from rather than where the cursor ended up.
+pub(crate) trait ByteReader {
+    fn u8(&mut self) -> Result<u8>;
+    fn i8(&mut self) -> Result<i8>;
+    fn u16(&mut self) -> Result<u16>;
+    fn u16_be(&mut self) -> Result<u16>;
+    fn i16(&mut self) -> Result<i16>;
+    fn u32(&mut self) -> Result<u32>;
+    fn i32(&mut self) -> Result<i32>;
+    fn f32(&mut self) -> Result<f32>;
+    fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>>;
+    /// Moves to `target`, failing if it lies past the end of the data.
+    fn seek_to(&mut self, target: u64) -> Result<()>;
+}
+
+impl<T: AsRef<[u8]>> ByteReader for Cursor<T> {
+    fn u8(&mut self) -> Result<u8> {
+        let offset = self.position();
+        self.read_u8().map_err(|e| Error::from_io(e, offset))
+    }
+    fn i8(&mut self) -> Result<i8> {
+        let offset = self.position();
+        self.read_i8().map_err(|e| Error::from_io(e, offset))
+    }
+    fn u16(&mut self) -> Result<u16> {
+        let offset = self.position();
+        self.read_u16::<LE>().map_err(|e| Error::from_io(e, offset))
+    }
+    fn u16_be(&mut self) -> Result<u16> {
+        let offset = self.position();
+        self.read_u16::<BE>().map_err(|e| Error::from_io(e, offset))
+    }
+    fn i16(&mut self) -> Result<i16> {
+        let offset = self.position();
+        self.read_i16::<LE>().map_err(|e| Error::from_io(e, offset))
+    }
+    fn u32(&mut self) -> Result<u32> {
+        let offset = self.position();
+        self.read_u32::<LE>().map_err(|e| Error::from_io(e, offset))
+    }
+    fn i32(&mut self) -> Result<i32> {
+        let offset = self.position();
+        self.read_i32::<LE>().map_err(|e| Error::from_io(e, offset))
+    }
+    fn f32(&mut self) -> Result<f32> {
+        let offset = self.position();
+        self.read_f32::<LE>().map_err(|e| Error::from_io(e, offset))
+    }
+    fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        let offset = self.position();
+        let remaining = (self.get_ref().as_ref().len() as u64).saturating_sub(offset);
+        if len as u64 > remaining {
+            return Err(Error::Truncated { offset });
+        }
+        let mut buf = vec![0u8; len];
+        self.read_exact(&mut buf).map_err(|e| Error::from_io(e, offset))?;
+        Ok(buf)
+    }
+    fn seek_to(&mut self, target: u64) -> Result<()> {
+        if target > self.get_ref().as_ref().len() as u64 {
+            return Err(Error::OffsetOutOfRange { offset: self.position(), target });
+        }
+        self.set_position(target);
+        Ok(())
+    }
+}
@@ -0,0 +1,728 @@
+use std::path::Path;
+
+use glam::*;
+
+use crate::anm::{self, Animation, Interpolate, Interpolation, Reduction, Sampling, Tolerance, Track, Wrap, FRAME_RATE};
+use crate::error::{Error, Result};
+use crate::ktmdl::KTModel;
+
+const ARRAY_BUFFER: u32 = 34962;
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+const UNSIGNED_SHORT: u32 = 5123;
+const UNSIGNED_INT: u32 = 5125;
+const FLOAT: u32 = 5126;
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_floats(v: &[f32]) -> String {
+    let v: Vec<String> = v.iter().map(|f| if f.is_finite() { f.to_string() } else { "0".to_string() }).collect();
+    format!("[{}]", v.join(","))
+}
+
+fn json_array(items: &[String]) -> String {
+    format!("[{}]", items.join(","))
+}
+
+/// A glTF 2.0 asset under construction. Buffer views, accessors and other
+/// objects are kept as rendered JSON and indexed in insertion order; all
+/// binary data goes into a single buffer.
+#[derive(Default, Clone)]
+pub struct Gltf {
+    pub bin: Vec<u8>,
+    buffer_views: Vec<String>,
+    accessors: Vec<String>,
+    nodes: Vec<String>,
+    meshes: Vec<String>,
+    materials: Vec<String>,
+    skins: Vec<String>,
+    animations: Vec<String>,
+    scene_nodes: Vec<usize>,
+}
+
+impl Gltf {
+    fn push_view(&mut self, data: &[u8], target: Option<u32>) -> usize {
+        self.bin.resize(self.bin.len().next_multiple_of(4), 0);
+        let offset = self.bin.len();
+        self.bin.extend_from_slice(data);
+        let target = target.map(|t| format!(",\"target\":{}", t)).unwrap_or_default();
+        self.buffer_views.push(format!(
+            "{{\"buffer\":0,\"byteOffset\":{},\"byteLength\":{}{}}}",
+            offset, data.len(), target
+        ));
+        self.buffer_views.len() - 1
+    }
+
+    fn push_accessor(&mut self, view: usize, component_type: u32, count: usize, kind: &str, bounds: Option<(&[f32], &[f32])>) -> usize {
+        let bounds = bounds
+            .map(|(min, max)| format!(",\"min\":{},\"max\":{}", json_floats(min), json_floats(max)))
+            .unwrap_or_default();
+        self.accessors.push(format!(
+            "{{\"bufferView\":{},\"componentType\":{},\"count\":{},\"type\":\"{}\"{}}}",
+            view, component_type, count, kind, bounds
+        ));
+        self.accessors.len() - 1
+    }
+
+    fn push_floats(&mut self, data: &[f32], width: usize, kind: &str, bounds: bool, target: Option<u32>) -> usize {
+        let count = data.len() / width;
+        let bounds = if bounds && count > 0 {
+            let mut min = vec![f32::MAX; width];
+            let mut max = vec![f32::MIN; width];
+            for v in data.chunks_exact(width) {
+                for i in 0..width {
+                    min[i] = min[i].min(v[i]);
+                    max[i] = max[i].max(v[i]);
+                }
+            }
+            Some((min, max))
+        } else {
+            None
+        };
+        let view = self.push_view(bytemuck::cast_slice(data), target);
+        self.push_accessor(view, FLOAT, count, kind, bounds.as_ref().map(|(a, b)| (a.as_slice(), b.as_slice())))
+    }
+
+    fn push_vec3s(&mut self, data: &[Vec3], bounds: bool, target: Option<u32>) -> usize {
+        let flat: Vec<f32> = data.iter().flat_map(|v| v.to_array()).collect();
+        self.push_floats(&flat, 3, "VEC3", bounds, target)
+    }
+
+    fn push_vec4s(&mut self, data: &[Vec4], target: Option<u32>) -> usize {
+        let flat: Vec<f32> = data.iter().flat_map(|v| v.to_array()).collect();
+        self.push_floats(&flat, 4, "VEC4", false, target)
+    }
+
+    fn push_vec2s(&mut self, data: &[Vec2]) -> usize {
+        let flat: Vec<f32> = data.iter().flat_map(|v| v.to_array()).collect();
+        self.push_floats(&flat, 2, "VEC2", false, Some(ARRAY_BUFFER))
+    }
+
+    fn push_mat4s(&mut self, data: &[Mat4]) -> usize {
+        let flat: Vec<f32> = data.iter().flat_map(|m| m.to_cols_array()).collect();
+        self.push_floats(&flat, 16, "MAT4", false, None)
+    }
+
+    fn push_joints(&mut self, data: &[[u16; 4]]) -> usize {
+        let flat: Vec<u16> = data.iter().flatten().copied().collect();
+        let view = self.push_view(bytemuck::cast_slice(&flat), Some(ARRAY_BUFFER));
+        self.push_accessor(view, UNSIGNED_SHORT, data.len(), "VEC4", None)
+    }
+
+    fn push_indices(&mut self, faces: &[[u32; 3]]) -> usize {
+        let flat: Vec<u32> = faces.iter().flatten().copied().collect();
+        if flat.iter().all(|&i| i <= u16::MAX as u32) {
+            let short: Vec<u16> = flat.iter().map(|&i| i as u16).collect();
+            let view = self.push_view(bytemuck::cast_slice(&short), Some(ELEMENT_ARRAY_BUFFER));
+            self.push_accessor(view, UNSIGNED_SHORT, flat.len(), "SCALAR", None)
+        } else {
+            let view = self.push_view(bytemuck::cast_slice(&flat), Some(ELEMENT_ARRAY_BUFFER));
+            self.push_accessor(view, UNSIGNED_INT, flat.len(), "SCALAR", None)
+        }
+    }
+
+    fn push_node(&mut self, json: String) -> usize {
+        self.nodes.push(json);
+        self.nodes.len() - 1
+    }
+
+    /// Renders the JSON part. `uri` names an external `.bin`; `None` leaves
+    /// the buffer to the GLB binary chunk.
+    pub fn json(&self, uri: Option<&str>) -> String {
+        let uri = uri.map(|u| format!(",\"uri\":{}", json_string(u))).unwrap_or_default();
+        let mut out = String::from("{\"asset\":{\"version\":\"2.0\",\"generator\":\"fuck_dance\"}");
+        out += &format!(",\"scene\":0,\"scenes\":[{{\"nodes\":{:?}}}]", self.scene_nodes);
+        out += &format!(",\"buffers\":[{{\"byteLength\":{}{}}}]", self.bin.len(), uri);
+        for (key, items) in [
+            ("bufferViews", &self.buffer_views),
+            ("accessors", &self.accessors),
+            ("nodes", &self.nodes),
+            ("meshes", &self.meshes),
+            ("materials", &self.materials),
+            ("skins", &self.skins),
+            ("animations", &self.animations),
+        ] {
+            if !items.is_empty() {
+                out += &format!(",\"{}\":{}", key, json_array(items));
+            }
+        }
+        out.push('}');
+        out
+    }
+
+    pub fn to_glb(&self) -> Vec<u8> {
+        let mut json = self.json(None).into_bytes();
+        json.resize(json.len().next_multiple_of(4), b' ');
+        let mut bin = self.bin.clone();
+        bin.resize(bin.len().next_multiple_of(4), 0);
+        let total = 12 + 8 + json.len() + 8 + bin.len();
+        let mut out = Vec::with_capacity(total);
+        out.extend_from_slice(b"glTF");
+        out.extend_from_slice(&2u32.to_le_bytes());
+        out.extend_from_slice(&(total as u32).to_le_bytes());
+        out.extend_from_slice(&(json.len() as u32).to_le_bytes());
+        out.extend_from_slice(b"JSON");
+        out.extend_from_slice(&json);
+        out.extend_from_slice(&(bin.len() as u32).to_le_bytes());
+        out.extend_from_slice(b"BIN\0");
+        out.extend_from_slice(&bin);
+        out
+    }
+
+    pub fn write_glb<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        Ok(std::fs::write(path, self.to_glb())?)
+    }
+
+    /// Writes `path` and a `.bin` next to it with the same file stem.
+    pub fn write_gltf<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let bin_path = path.with_extension("bin");
+        let uri = bin_path.file_name().and_then(|n| n.to_str()).unwrap_or("model.bin");
+        std::fs::write(&bin_path, &self.bin)?;
+        Ok(std::fs::write(path, self.json(Some(uri)))?)
+    }
+}
+
+/// Builds a skinned glTF asset from `model`: one node per bone carrying its
+/// local bind transform, one mesh with a primitive per submesh, and a skin
+/// whose joint `i` is bone `i`. Coordinates are kept as stored in the
+/// `.model`, which is already right-handed and Y-up.
+pub fn from_ktmodel(model: &KTModel) -> Gltf {
+    let mut gltf = Gltf::default();
+    let bone_count = model.bone_pos.len();
+
+    let bone_name = |i: usize| model.bone_names.get(i).cloned().unwrap_or_else(|| format!("bone_{}", i));
+    let mut children = vec![Vec::new(); bone_count];
+    for (i, p) in model.bone_parent.iter().enumerate() {
+        if let Some(p) = *p {
+            children[p].push(i);
+        }
+    }
+    for i in 0..bone_count {
+        let (scale, rot, trans) = model.bone_local_matrix(i).to_scale_rotation_translation();
+        let children = if children[i].is_empty() {
+            String::new()
+        } else {
+            format!(",\"children\":{:?}", children[i])
+        };
+        gltf.push_node(format!(
+            "{{\"name\":{},\"translation\":{},\"rotation\":{},\"scale\":{}{}}}",
+            json_string(&bone_name(i)),
+            json_floats(&trans.to_array()),
+            json_floats(&rot.normalize().to_array()),
+            json_floats(&scale.to_array()),
+            children
+        ));
+    }
+
+    let mut primitives = Vec::new();
+    for (i, m) in model.meshs.iter().enumerate() {
+        let pos: Vec<Vec3> = m.verts.iter().map(|v| v.pos).collect();
+        let nrm: Vec<Vec3> = m.verts.iter().map(|v| v.norm.normalize_or(Vec3::Y)).collect();
+        let uv: Vec<Vec2> = m.verts.iter().map(|v| v.uv).collect();
+        let mut attributes = vec![
+            format!("\"POSITION\":{}", gltf.push_vec3s(&pos, true, Some(ARRAY_BUFFER))),
+            format!("\"NORMAL\":{}", gltf.push_vec3s(&nrm, false, Some(ARRAY_BUFFER))),
+            format!("\"TEXCOORD_0\":{}", gltf.push_vec2s(&uv)),
+        ];
+        if m.verts.iter().all(|v| v.tang.length_squared() > 0.0) {
+            // The handedness sign says whether the stored bitangent is
+            // cross(normal, tangent) or its opposite.
+            let tang: Vec<Vec4> = m.verts.iter().map(|v| {
+                let w = if v.norm.cross(v.tang).dot(v.bitang) < 0.0 { -1.0 } else { 1.0 };
+                v.tang.normalize().extend(w)
+            }).collect();
+            attributes.push(format!("\"TANGENT\":{}", gltf.push_vec4s(&tang, Some(ARRAY_BUFFER))));
+        }
+        if bone_count > 0 {
+            let mut joints = Vec::with_capacity(m.verts.len());
+            let mut weights = Vec::with_capacity(m.verts.len());
+            for v in &m.verts {
+                let mut w = v.bone_weight.max(Vec4::ZERO);
+                let mut j = [0u16; 4];
+                for k in 0..4 {
+                    let b = v.bone_index[k];
+                    if w[k] == 0.0 || b < 0 || b as usize >= bone_count {
+                        w[k] = 0.0;
+                    } else {
+                        j[k] = b as u16;
+                    }
+                }
+                let sum = w.element_sum();
+                joints.push(j);
+                weights.push(if sum > 0.0 { w / sum } else { Vec4::X });
+            }
+            attributes.push(format!("\"JOINTS_0\":{}", gltf.push_joints(&joints)));
+            attributes.push(format!("\"WEIGHTS_0\":{}", gltf.push_vec4s(&weights, Some(ARRAY_BUFFER))));
+        }
+        let indices = gltf.push_indices(&m.face);
+        gltf.materials.push(format!("{{\"name\":{},\"doubleSided\":true}}", json_string(&i.to_string())));
+        primitives.push(format!(
+            "{{\"attributes\":{{{}}},\"indices\":{},\"material\":{}}}",
+            attributes.join(","), indices, i
+        ));
+    }
+    let skin = if bone_count > 0 {
+        let ibm: Vec<Mat4> = (0..bone_count).map(|i| model.inverse_bind_matrix(i)).collect();
+        let ibm = gltf.push_mat4s(&ibm);
+        let roots: Vec<usize> = (0..bone_count).filter(|&i| model.bone_parent[i].is_none()).collect();
+        let skeleton = roots.first().map(|r| format!(",\"skeleton\":{}", r)).unwrap_or_default();
+        gltf.skins.push(format!(
+            "{{\"inverseBindMatrices\":{},\"joints\":{:?}{}}}",
+            ibm, (0..bone_count).collect::<Vec<_>>(), skeleton
+        ));
+        gltf.scene_nodes.extend(roots);
+        ",\"skin\":0"
+    } else {
+        ""
+    };
+    // glTF does not allow a mesh without primitives.
+    if !primitives.is_empty() {
+        gltf.meshes.push(format!("{{\"name\":\"ktmdl\",\"primitives\":{}}}", json_array(&primitives)));
+        let mesh_node = gltf.push_node(format!("{{\"name\":\"ktmdl\",\"mesh\":0{}}}", skin));
+        gltf.scene_nodes.push(mesh_node);
+    }
+    gltf
+}
+
+impl Gltf {
+    /// Adds `anm` as an animation clip named `name` on an asset built by
+    /// [`from_ktmodel`] from the same `model`. Sparse tracks, which hold
+    /// each key until the next, are sampled with STEP and dense ones with
+    /// LINEAR. A track value is the bone's local transform, except on a
+    /// root bone where it applies on top of the bind transform, so root keys
+    /// are folded into the bind matrix first. Bones without a track keep
+    /// their bind pose, including before the first key of a sparse track.
+    ///
+    /// With the default `sampling` the keys are written as they are. Any
+    /// other interpolation, wrap mode or frame rate is baked at
+    /// `sampling.fps`; a looped
+    /// clip gets a closing sample at the loop point so players that repeat
+    /// it blend the last frame back into the first.
+    ///
+    /// Given a `tolerance`, keys that the written STEP or LINEAR sampler
+    /// rebuilds within it are dropped, and the key counts of every animated
+    /// channel are returned. Distances are in metres.
+    pub fn add_animation(
+        &mut self, model: &KTModel, name: &str, anm: &Animation, sampling: &Sampling, tolerance: Option<&Tolerance>,
+    ) -> Result<Vec<Reduction>> {
+        if anm.bones.len() != model.bone_matrix.len() {
+            return Err(Error::InvalidValue { offset: 0x20, what: "bone count (does not match .model)" });
+        }
+        let frame_count = anm.frame_count();
+        let mut reductions = Vec::new();
+        let mut samplers = Vec::new();
+        let mut channels = Vec::new();
+        for (i, tracks) in anm.bones.iter().enumerate() {
+            let root = model.bone_parent[i].is_none();
+            let (_, bind_rot, bind_trans) = model.bone_local_matrix(i).to_scale_rotation_translation();
+
+            if let Some(track) = &tracks.rotation {
+                let (default, to_node) = if root {
+                    (Quat::IDENTITY, bind_rot)
+                } else {
+                    (bind_rot, Quat::IDENTITY)
+                };
+                let (times, mut values, step) = track_keys(track, default, sampling, frame_count);
+                let mut prev = Quat::IDENTITY;
+                for v in &mut values {
+                    *v = (to_node * v.normalize()).normalize();
+                    if v.dot(prev) < 0.0 {
+                        *v = -*v;
+                    }
+                    prev = *v;
+                }
+                let (times, values) = reduce(&mut reductions, tolerance, track_name(model, i, "rotation"), times, values, step);
+                let values: Vec<Vec4> = values.into_iter().map(Vec4::from).collect();
+                let input = self.push_floats(&times, 1, "SCALAR", true, None);
+                let output = self.push_vec4s(&values, None);
+                samplers.push(sampler_json(input, output, step));
+                channels.push(format!("{{\"sampler\":{},\"target\":{{\"node\":{},\"path\":\"rotation\"}}}}", samplers.len() - 1, i));
+            }
+            if let Some(track) = &tracks.translation {
+                let default = if root { Vec3::ZERO } else { bind_trans };
+                let (times, mut values, step) = track_keys(track, default, sampling, frame_count);
+                if root {
+                    for v in &mut values {
+                        *v = model.bone_matrix[i].transform_point3(*v);
+                    }
+                }
+                let (times, values) = reduce(&mut reductions, tolerance, track_name(model, i, "translation"), times, values, step);
+                let input = self.push_floats(&times, 1, "SCALAR", true, None);
+                let output = self.push_vec3s(&values, false, None);
+                samplers.push(sampler_json(input, output, step));
+                channels.push(format!("{{\"sampler\":{},\"target\":{{\"node\":{},\"path\":\"translation\"}}}}", samplers.len() - 1, i));
+            }
+        }
+        if channels.is_empty() {
+            return Ok(reductions);
+        }
+        self.animations.push(format!(
+            "{{\"name\":{},\"samplers\":{},\"channels\":{}}}",
+            json_string(name), json_array(&samplers), json_array(&channels)
+        ));
+        Ok(reductions)
+    }
+}
+
+fn track_name(model: &KTModel, bone: usize, path: &str) -> String {
+    match model.bone_names.get(bone) {
+        Some(name) => format!("{} {}", name, path),
+        None => format!("bone {} {}", bone, path),
+    }
+}
+
+/// Applies `tolerance`, if any, to one sampler's keys and records the
+/// result. glTF slerps LINEAR rotations, which `Slerp` also covers for
+/// translations.
+fn reduce<T: Interpolate>(
+    reductions: &mut Vec<Reduction>, tolerance: Option<&Tolerance>, track: String, times: Vec<f32>, values: Vec<T>, step: bool,
+) -> (Vec<f32>, Vec<T>) {
+    let Some(tolerance) = tolerance else {
+        return (times, values);
+    };
+    let interpolation = if step { Interpolation::Step } else { Interpolation::Slerp };
+    let kept = anm::reduce_keys(&times, &values, interpolation, tolerance);
+    reductions.push(Reduction { track, before: times.len(), after: kept.len() });
+    (kept.iter().map(|&i| times[i]).collect(), kept.iter().map(|&i| values[i]).collect())
+}
+
+fn sampler_json(input: usize, output: usize, step: bool) -> String {
+    let interpolation = if step { "STEP" } else { "LINEAR" };
+    format!("{{\"input\":{},\"output\":{},\"interpolation\":\"{}\"}}", input, output, interpolation)
+}
+
+/// Key times in seconds and values of `track`, and whether to sample them
+/// with STEP. `default` stands in where the track has no value, such as
+/// before the first key of a sparse track.
+fn track_keys<T: Interpolate>(track: &Track<T>, default: T, sampling: &Sampling, frame_count: u32) -> (Vec<f32>, Vec<T>, bool) {
+    let step = sampling.interpolation_for(track) == Interpolation::Step;
+    if sampling.interpolation.is_some() || sampling.wrap != Wrap::Clamp || sampling.fps != FRAME_RATE {
+        let mut frames = sampling.frame_times(frame_count);
+        if sampling.wrap == Wrap::Loop {
+            frames.push(frame_count.max(1) as f32);
+        }
+        let values = frames.iter().map(|&f| track.sample(f, sampling, frame_count).unwrap_or(default)).collect();
+        let times = frames.iter().map(|f| f / FRAME_RATE).collect();
+        return (times, values, step);
+    }
+
+    let mut times = Vec::with_capacity(track.keys.len() + 1);
+    let mut values = Vec::with_capacity(track.keys.len() + 1);
+    if track.keys.first().is_none_or(|k| k.frame > 0) {
+        times.push(0.0);
+        values.push(default);
+    }
+    for k in &track.keys {
+        times.push(k.frame as f32 / FRAME_RATE);
+        values.push(k.value);
+    }
+    (times, values, step)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter::Peekable;
+    use std::ops::Index;
+    use std::str::Chars;
+
+    use super::*;
+    use crate::anm::{BoneTracks, Key, TRACK_FLOAT_TRANSLATION, TRACK_ROTATION};
+    use crate::ktmdl::{KTSubMesh, KTVertex};
+
+    /// Just enough JSON to read back what `Gltf::json` renders.
+    #[derive(Debug, PartialEq)]
+    enum Json {
+        Null,
+        Bool(bool),
+        Num(f64),
+        Str(String),
+        Arr(Vec<Json>),
+        Obj(Vec<(String, Json)>),
+    }
+
+    impl Json {
+        fn parse(s: &str) -> Json {
+            let mut chars = s.chars().peekable();
+            let value = Self::value(&mut chars);
+            Self::skip_ws(&mut chars);
+            assert_eq!(chars.next(), None, "trailing characters");
+            value
+        }
+
+        fn skip_ws(chars: &mut Peekable<Chars>) {
+            while chars.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+        }
+
+        fn expect(chars: &mut Peekable<Chars>, c: char) {
+            Self::skip_ws(chars);
+            assert_eq!(chars.next(), Some(c));
+        }
+
+        fn value(chars: &mut Peekable<Chars>) -> Json {
+            Self::skip_ws(chars);
+            match *chars.peek().expect("unexpected end") {
+                '{' => {
+                    chars.next();
+                    let mut members = Vec::new();
+                    Self::skip_ws(chars);
+                    if chars.next_if_eq(&'}').is_none() {
+                        loop {
+                            let Json::Str(key) = Self::value(chars) else { panic!("object key is not a string") };
+                            Self::expect(chars, ':');
+                            members.push((key, Self::value(chars)));
+                            Self::skip_ws(chars);
+                            match chars.next() {
+                                Some(',') => {},
+                                Some('}') => break,
+                                c => panic!("unexpected {:?} in object", c),
+                            }
+                        }
+                    }
+                    Json::Obj(members)
+                },
+                '[' => {
+                    chars.next();
+                    let mut items = Vec::new();
+                    Self::skip_ws(chars);
+                    if chars.next_if_eq(&']').is_none() {
+                        loop {
+                            items.push(Self::value(chars));
+                            Self::skip_ws(chars);
+                            match chars.next() {
+                                Some(',') => {},
+                                Some(']') => break,
+                                c => panic!("unexpected {:?} in array", c),
+                            }
+                        }
+                    }
+                    Json::Arr(items)
+                },
+                '"' => {
+                    chars.next();
+                    let mut out = String::new();
+                    loop {
+                        match chars.next().expect("unterminated string") {
+                            '"' => break,
+                            '\\' => match chars.next() {
+                                Some('n') => out.push('\n'),
+                                Some('r') => out.push('\r'),
+                                Some('t') => out.push('\t'),
+                                Some('u') => {
+                                    let hex: String = chars.by_ref().take(4).collect();
+                                    out.push(char::from_u32(u32::from_str_radix(&hex, 16).unwrap()).unwrap());
+                                },
+                                Some(c) => out.push(c),
+                                None => panic!("unterminated escape"),
+                            },
+                            c => {
+                                assert!(c as u32 >= 0x20, "control character in string");
+                                out.push(c);
+                            },
+                        }
+                    }
+                    Json::Str(out)
+                },
+                c if c.is_ascii_alphabetic() => {
+                    let mut word = String::new();
+                    while let Some(c) = chars.next_if(|c| c.is_ascii_alphabetic()) {
+                        word.push(c);
+                    }
+                    match word.as_str() {
+                        "null" => Json::Null,
+                        "true" => Json::Bool(true),
+                        "false" => Json::Bool(false),
+                        _ => panic!("unexpected {:?}", word),
+                    }
+                },
+                _ => {
+                    let mut number = String::new();
+                    while let Some(c) = chars.next_if(|c| c.is_ascii_digit() || "+-.eE".contains(*c)) {
+                        number.push(c);
+                    }
+                    Json::Num(number.parse().unwrap_or_else(|_| panic!("bad number {:?}", number)))
+                },
+            }
+        }
+
+        fn num(&self) -> f64 {
+            match self {
+                Json::Num(n) => *n,
+                _ => panic!("{:?} is not a number", self),
+            }
+        }
+
+        fn str(&self) -> &str {
+            match self {
+                Json::Str(s) => s,
+                _ => panic!("{:?} is not a string", self),
+            }
+        }
+
+        fn len(&self) -> usize {
+            match self {
+                Json::Arr(items) => items.len(),
+                _ => panic!("{:?} is not an array", self),
+            }
+        }
+    }
+
+    impl Index<&str> for Json {
+        type Output = Json;
+
+        fn index(&self, key: &str) -> &Json {
+            let Json::Obj(members) = self else { panic!("{:?} is not an object", self) };
+            &members.iter().find(|(k, _)| k == key).unwrap_or_else(|| panic!("no {:?}", key)).1
+        }
+    }
+
+    impl Index<usize> for Json {
+        type Output = Json;
+
+        fn index(&self, i: usize) -> &Json {
+            let Json::Arr(items) = self else { panic!("{:?} is not an array", self) };
+            &items[i]
+        }
+    }
+
+    /// A root bone with a child one unit up, skinning a single triangle.
+    fn model() -> KTModel {
+        let vertex = |pos: Vec3, bone: i32| KTVertex {
+            pos,
+            bone_index: ivec4(bone, 0, 0, 0),
+            bone_weight: Vec4::X,
+            norm: Vec3::Z,
+            uv: pos.truncate(),
+            ..Default::default()
+        };
+        KTModel {
+            bone_names: vec!["root".to_string(), "child".to_string()],
+            bone_pos: vec![Vec3::ZERO, Vec3::Y],
+            bone_parent: vec![None, Some(0)],
+            bone_matrix: vec![Mat4::IDENTITY, Mat4::from_translation(Vec3::Y)],
+            bone_unknown: vec![Default::default(); 2],
+            meshs: vec![KTSubMesh {
+                verts: vec![vertex(Vec3::ZERO, 0), vertex(Vec3::X, 0), vertex(Vec3::Y, 1)],
+                face: vec![[0, 1, 2]],
+            }],
+        }
+    }
+
+    fn track<T: Copy>(kind: u16, sparse: bool, keys: &[(u32, T)]) -> Track<T> {
+        Track {
+            kind,
+            interpolation: if sparse { 0 } else { 1 },
+            unknown: 0,
+            keys: keys.iter().map(|&(frame, value)| Key { frame, value }).collect(),
+        }
+    }
+
+    /// The accessor `i` as parsed from `json`.
+    fn accessor<'a>(json: &'a Json, i: &Json) -> &'a Json {
+        &json["accessors"][i.num() as usize]
+    }
+
+    #[test]
+    fn animation_samplers() {
+        let model = model();
+        // A sparse root rotation starting on frame 2, and a dense child.
+        let anm = Animation {
+            max_frame: 9,
+            bones: vec![
+                BoneTracks {
+                    rotation: Some(track(TRACK_ROTATION, true, &[(2, Quat::from_rotation_y(0.5)), (5, Quat::from_rotation_y(1.0))])),
+                    translation: None,
+                },
+                BoneTracks {
+                    rotation: Some(track(TRACK_ROTATION, false, &(0..10).map(|f| (f, Quat::from_rotation_x(f as f32 * 0.1))).collect::<Vec<_>>())),
+                    translation: Some(track(TRACK_FLOAT_TRANSLATION, false, &(0..10).map(|f| (f, vec3(0.0, 1.0, f as f32))).collect::<Vec<_>>())),
+                },
+            ],
+            header: Vec::new(),
+        };
+        let mut gltf = from_ktmodel(&model);
+        for name in ["walk", "run \"fast\""] {
+            assert!(gltf.add_animation(&model, name, &anm, &Sampling::default(), None).unwrap().is_empty());
+        }
+        let json = Json::parse(&gltf.json(None));
+        let animations = &json["animations"];
+        assert_eq!(animations.len(), 2);
+        assert_eq!((animations[0]["name"].str(), animations[1]["name"].str()), ("walk", "run \"fast\""));
+
+        let animation = &animations[1];
+        let expected = [("STEP", 0, "rotation", 3), ("LINEAR", 1, "rotation", 10), ("LINEAR", 1, "translation", 10)];
+        assert_eq!(animation["samplers"].len(), expected.len());
+        for (i, (interpolation, node, path, count)) in expected.into_iter().enumerate() {
+            let (sampler, channel) = (&animation["samplers"][i], &animation["channels"][i]);
+            assert_eq!(sampler["interpolation"].str(), interpolation);
+            assert_eq!(channel["sampler"].num() as usize, i);
+            assert_eq!((channel["target"]["node"].num() as usize, channel["target"]["path"].str()), (node, path));
+            let (input, output) = (accessor(&json, &sampler["input"]), accessor(&json, &sampler["output"]));
+            assert_eq!((input["count"].num() as usize, output["count"].num() as usize), (count, count));
+            assert_eq!(input["type"].str(), "SCALAR");
+            assert_eq!(output["type"].str(), if path == "rotation" { "VEC4" } else { "VEC3" });
+        }
+        // The sparse track holds the bind pose until its first key.
+        let input = accessor(&json, &animation["samplers"][0]["input"]);
+        assert_eq!((input["min"][0].num(), input["max"][0].num() as f32), (0.0, 5.0 / FRAME_RATE));
+    }
+
+    #[test]
+    fn track_keys_resample_frame_rate() {
+        let keys: Vec<(u32, Quat)> = (0..10).map(|f| (f, Quat::from_rotation_y(f as f32 * 0.1))).collect();
+        let track = track(TRACK_ROTATION, false, &keys);
+        let (times, values, step) = track_keys(&track, Quat::IDENTITY, &Sampling::default(), 10);
+        assert_eq!((times.len(), values.len(), step), (10, 10, false));
+
+        let sampling = Sampling { fps: 60.0, ..Sampling::default() };
+        let (times, values, _) = track_keys(&track, Quat::IDENTITY, &sampling, 10);
+        assert_eq!((times.len(), values.len()), (19, 19));
+        for (t, f) in times.iter().zip(sampling.frame_times(10)) {
+            assert_eq!(*t, f / FRAME_RATE);
+        }
+        assert!(values[1].abs_diff_eq(Quat::from_rotation_y(0.05), 1e-5));
+    }
+
+    #[test]
+    fn glb_layout() {
+        let model = model();
+        let glb = from_ktmodel(&model).to_glb();
+        let u32_at = |i: usize| u32::from_le_bytes(glb[i..i + 4].try_into().unwrap()) as usize;
+        assert_eq!(&glb[0..4], b"glTF");
+        assert_eq!((u32_at(4), u32_at(8)), (2, glb.len()));
+        let json_len = u32_at(12);
+        assert_eq!(&glb[16..20], b"JSON");
+        let bin_start = 20 + json_len;
+        let bin_len = u32_at(bin_start);
+        assert_eq!(&glb[bin_start + 4..bin_start + 8], b"BIN\0");
+        assert_eq!(bin_start + 8 + bin_len, glb.len());
+        assert_eq!((json_len % 4, bin_len % 4), (0, 0));
+
+        let json = Json::parse(std::str::from_utf8(&glb[20..bin_start]).unwrap());
+        assert!(json["buffers"][0]["byteLength"].num() as usize <= bin_len);
+        let attributes = &json["meshes"][0]["primitives"][0]["attributes"];
+        for name in ["POSITION", "NORMAL", "TEXCOORD_0", "JOINTS_0", "WEIGHTS_0"] {
+            assert_eq!(accessor(&json, &attributes[name])["count"].num(), 3.0, "{}", name);
+        }
+        let skin = &json["skins"][0];
+        assert_eq!(skin["joints"].len(), model.bone_pos.len());
+        let ibm = accessor(&json, &skin["inverseBindMatrices"]);
+        assert_eq!((ibm["count"].num() as usize, ibm["type"].str()), (model.bone_pos.len(), "MAT4"));
+        assert_eq!(json["nodes"][1]["name"].str(), "child");
+    }
+}
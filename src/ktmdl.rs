@@ -1,276 +1,426 @@
-use std::{io::{Seek, SeekFrom, BufRead}, collections::BTreeSet};
+use std::{io::Cursor, collections::BTreeSet};
 
-use byteorder::{ReadBytesExt, LE};
 use glam::*;
 
+use crate::arc::read_string_to_null;
+use crate::error::{ByteReader, Error, Result};
 use crate::pmx;
 
-fn read_vec3f(reader: &mut std::io::Cursor<Vec<u8>>) -> Vec3 {
-    let x = reader.read_f32::<LE>().unwrap();
-    let y = reader.read_f32::<LE>().unwrap();
-    let z = reader.read_f32::<LE>().unwrap();
-    vec3(x, y, z)
+fn read_vec3f<T: AsRef<[u8]>>(reader: &mut Cursor<T>) -> Result<Vec3> {
+    let x = reader.f32()?;
+    let y = reader.f32()?;
+    let z = reader.f32()?;
+    Ok(vec3(x, y, z))
 }
 
-pub fn ktmodel_to_pmx(content: Vec<u8>, bone_names: Vec<String>, save_path: &str) {
-    let mut ktmodel = KTModel::default();
-    ktmodel.bone_names = bone_names;
+fn read_vec4f<T: AsRef<[u8]>>(reader: &mut Cursor<T>) -> Result<Vec4> {
+    let x = reader.f32()?;
+    let y = reader.f32()?;
+    let z = reader.f32()?;
+    let w = reader.f32()?;
+    Ok(vec4(x, y, z, w))
+}
 
+/// Converts a `.model` into `<save_path>.pmx`. With `tangents`, each vertex
+/// also carries its tangent in appendix UV1 and bitangent in UV2, as xyz
+/// with w = 0. `options` picks the text encoding and index sizes.
+pub fn ktmodel_to_pmx(
+    content: Vec<u8>,
+    bone_names: Vec<String>,
+    save_path: &str,
+    tangents: bool,
+    options: &pmx::WriteOptions,
+) -> Result<()> {
+    let mut ktmodel = KTModel::read(content)?;
+    ktmodel.set_bone_names(bone_names)?;
 
-    let mut reader = std::io::Cursor::new(content);
-    reader.set_position(0x18);
-    let bone_count = reader.read_u32::<LE>().unwrap();
-    let bone_ptr = reader.read_u32::<LE>().unwrap();
-    // eprintln!("0x18: bone count: {}, ptr: {:X}", bone_count, bone_ptr);
+    let data = mmd_pmx(&ktmodel, save_path, tangents).write_with(options);
+    let write_path = save_path.to_string() + ".pmx";
+    std::fs::write(write_path, data)?;
+    Ok(())
+}
 
-    reader.set_position(0x20);
-    let bone_mapping_section_count = reader.read_u32::<LE>().unwrap() as usize;
-    let mut bone_map_section_to_batch = Vec::new();
-
-    for i in 0..bone_count {
-        let cur_bone_ptr = bone_ptr + 16 * 11 * i;
-        reader.set_position((16 * 4 + cur_bone_ptr) as _);
-        ktmodel.bone_pos.push(read_vec3f(&mut reader));
-        reader.set_position((16 * 8 + cur_bone_ptr) as _);
-        reader.set_position((16 * 9 + cur_bone_ptr) as _);
-        reader.set_position((16 * 10 + 12 + cur_bone_ptr) as _);
-        let p = reader.read_i32::<LE>().unwrap();
-        ktmodel.bone_parent.push(if p == -1 {
-            None
-        } else {
-            Some(p as usize)
-        });
+/// `ktmodel` as a PMX in MMD units and handedness.
+fn mmd_pmx(ktmodel: &KTModel, comment: &str, tangents: bool) -> pmx::Pmx {
+    let mut pmx_mdl = ktmodel.to_pmx(comment, tangents);
+    pmx_mdl.scale(12.5);
+    pmx_mdl.right_hand();
+    // `right_hand` leaves appendix UVs alone; these are directions and
+    // mirror like the normals.
+    for v in &mut pmx_mdl.verts {
+        for uv in &mut v.appendix_uv[..pmx_mdl.appendix_uv_count as usize] {
+            uv.z *= -1.0;
+        }
     }
-    assert_eq!(ktmodel.bone_names.len(), ktmodel.bone_pos.len());
-
-    reader.set_position(0x28);
-    let section_count = reader.read_u32::<LE>().unwrap();
-    // eprintln!("0x28: section_count: {}", section_count);
-
-    reader.set_position(0x34);
-    let section_ptr = reader.read_u32::<LE>().unwrap();
-    // eprintln!("0x34: section_ptr: {:X}", section_ptr);
-
-    let mut bone_mapping_section = Vec::<BTreeSet<i32>>::new();
-
-    for i in 0..section_count {
-        let mut set = BTreeSet::<i32>::new();
-        let mut mesh = KTSubMesh::default();
-        reader.set_position((i * 64 + section_ptr) as _);
-
-        let vert_offset = reader.read_u32::<LE>().unwrap();
-        let vert_count = reader.read_u32::<LE>().unwrap();
-        reader.read_u8().unwrap();
-        let fvf_size = reader.read_u8().unwrap() as u32;
-        assert!(fvf_size == 68 || fvf_size == 44);
-        reader.seek(SeekFrom::Current(22)).unwrap();
-
-        let face_offset = reader.read_u32::<LE>().unwrap();
-        let face_count = reader.read_u32::<LE>().unwrap() / 3;
-
-        for j in 0..vert_count {
-            let vert_start = section_ptr + i * 64 + vert_offset + j * fvf_size;
-            reader.set_position(vert_start as _);
-            let pos = read_vec3f(&mut reader);
-            let b_0 = reader.read_u8().unwrap() as i32;
-            let b_1 = reader.read_u8().unwrap() as i32;
-            let b_2 = reader.read_u8().unwrap() as i32;
-            let b_3 = reader.read_u8().unwrap() as i32;
-            let bone_index = ivec4(b_0, b_1, b_2, b_3);
-            let bw = read_vec3f(&mut reader);
-            let bone_weight = vec4(1.0 - bw.x - bw.y - bw.z, bw.x, bw.y, bw.z);
-            let norm = read_vec3f(&mut reader);
-            let tang = if fvf_size == 68 { read_vec3f(&mut reader) } else { Vec3::ZERO };
-            let bitang = if fvf_size == 68 { read_vec3f(&mut reader) } else { Vec3::ZERO };
-            let u = half::f16::from_bits(reader.read_u16::<LE>().unwrap()).to_f32();
-            let v = half::f16::from_bits(reader.read_u16::<LE>().unwrap()).to_f32();
-            let uv = vec2(u, v);
-            mesh.verts.push(KTVertex{ pos, bone_index, bone_weight, norm, tang, bitang, uv });
-            set.insert(b_0);
-            if b_1 != 0 {
-                set.insert(b_1);
-            }
-            if b_2 != 0 {
-                set.insert(b_2);
+    pmx_mdl
+}
+
+impl KTModel {
+    /// Parses a `.model` file. Bone names live in the companion `.b2it`
+    /// and are left empty here; vertex bone indices are remapped from the
+    /// per-batch palette to global bone indices.
+    pub fn read(content: Vec<u8>) -> Result<Self> {
+        let mut ktmodel = KTModel::default();
+
+        let mut reader = Cursor::new(content);
+        reader.set_position(0x18);
+        let bone_count = reader.u32()?;
+        let bone_ptr = reader.u32()? as u64;
+        // eprintln!("0x18: bone count: {}, ptr: {:X}", bone_count, bone_ptr);
+
+        reader.set_position(0x20);
+        let bone_mapping_section_count = reader.u32()? as usize;
+        let mut bone_map_section_to_batch = Vec::new();
+
+        for i in 0..bone_count as u64 {
+            let cur_bone_ptr = bone_ptr + 16 * 11 * i;
+            let mut unknown = KTBoneUnknown::default();
+            reader.seek_to(cur_bone_ptr)?;
+            unknown.row0.copy_from_slice(&reader.read_bytes(16)?);
+            let mut cols = [Vec4::ZERO; 4];
+            for c in &mut cols {
+                *c = read_vec4f(&mut reader)?;
+                for j in 0..4 {
+                    if c[j].abs() < 0.00001 {
+                        c[j] = 0.0;
+                    }
+                }
             }
-            if b_3 != 0 {
-                set.insert(b_3);
+            ktmodel.bone_matrix.push(Mat4::from_cols(cols[0], cols[1], cols[2], cols[3]));
+            reader.seek_to(16 * 4 + cur_bone_ptr)?;
+            ktmodel.bone_pos.push(read_vec3f(&mut reader)?);
+            reader.seek_to(16 * 5 + cur_bone_ptr)?;
+            for row in &mut unknown.rows5_9 {
+                row.copy_from_slice(&reader.read_bytes(16)?);
             }
+            unknown.row10.copy_from_slice(&reader.read_bytes(12)?);
+            ktmodel.bone_unknown.push(unknown);
+            reader.seek_to(16 * 10 + 12 + cur_bone_ptr)?;
+            let p = reader.i32()?;
+            ktmodel.bone_parent.push(if p == -1 {
+                None
+            } else if p >= 0 && (p as u32) < bone_count {
+                Some(p as usize)
+            } else {
+                return Err(Error::InvalidValue { offset: 16 * 10 + 12 + cur_bone_ptr, what: "bone parent index" });
+            });
         }
-        println!("fuck: {}: {:?}", i, set);
-        let max_value = *set.iter().max().unwrap();
-        if max_value + 1 == set.len() as _ {
-            bone_mapping_section.push(BTreeSet::new());
+
+        reader.set_position(0x28);
+        let section_count = reader.u32()? as u64;
+        // eprintln!("0x28: section_count: {}", section_count);
+
+        reader.set_position(0x34);
+        let section_ptr = reader.u32()? as u64;
+        // eprintln!("0x34: section_ptr: {:X}", section_ptr);
+
+        let mut bone_mapping_section = Vec::<BTreeSet<i32>>::new();
+
+        for i in 0..section_count {
+            let mut set = BTreeSet::<i32>::new();
+            let mut mesh = KTSubMesh::default();
+            let section_start = i * 64 + section_ptr;
+            reader.seek_to(section_start)?;
+
+            let vert_offset = reader.u32()? as u64;
+            let vert_count = reader.u32()? as u64;
+            reader.u8()?;
+            let fvf_offset = reader.position();
+            let fvf_size = reader.u8()?;
+            if fvf_size != 68 && fvf_size != 44 {
+                return Err(Error::UnsupportedFvfSize { offset: fvf_offset, size: fvf_size });
+            }
+            reader.set_position(reader.position() + 22);
+
+            let face_offset = reader.u32()? as u64;
+            let face_count = reader.u32()? as u64 / 3;
+
+            for j in 0..vert_count {
+                let vert_start = section_start + vert_offset + j * fvf_size as u64;
+                reader.seek_to(vert_start)?;
+                let pos = read_vec3f(&mut reader)?;
+                let b_0 = reader.u8()? as i32;
+                let b_1 = reader.u8()? as i32;
+                let b_2 = reader.u8()? as i32;
+                let b_3 = reader.u8()? as i32;
+                let bone_index = ivec4(b_0, b_1, b_2, b_3);
+                let bw = read_vec3f(&mut reader)?;
+                let bone_weight = vec4(1.0 - bw.x - bw.y - bw.z, bw.x, bw.y, bw.z);
+                let norm = read_vec3f(&mut reader)?;
+                let tang = if fvf_size == 68 { read_vec3f(&mut reader)? } else { Vec3::ZERO };
+                let bitang = if fvf_size == 68 { read_vec3f(&mut reader)? } else { Vec3::ZERO };
+                let u = half::f16::from_bits(reader.u16()?).to_f32();
+                let v = half::f16::from_bits(reader.u16()?).to_f32();
+                let uv = vec2(u, v);
+                mesh.verts.push(KTVertex{ pos, bone_index, bone_weight, norm, tang, bitang, uv });
+                set.insert(b_0);
+                if b_1 != 0 {
+                    set.insert(b_1);
+                }
+                if b_2 != 0 {
+                    set.insert(b_2);
+                }
+                if b_3 != 0 {
+                    set.insert(b_3);
+                }
+            }
+            let max_value = set.iter().max().copied().unwrap_or(-1);
+            if max_value + 1 == set.len() as _ {
+                bone_mapping_section.push(BTreeSet::new());
+            }
+            let Some(batch) = bone_mapping_section.last_mut() else {
+                return Err(Error::InvalidValue { offset: section_start, what: "bone palette" });
+            };
+            batch.extend(&set);
+
+            bone_map_section_to_batch.push(bone_mapping_section.len() - 1);
+
+
+            for j in 0..face_count {
+                let face_start = section_start + 32 + face_offset + j * 6;
+                reader.seek_to(face_start)?;
+                let f0 = reader.u16()? as u32;
+                let f1 = reader.u16()? as u32;
+                let f2 = reader.u16()? as u32;
+                mesh.face.push([f0, f1, f2]);
+            }
+            ktmodel.meshs.push(mesh);
         }
-        for v in &set {
-            bone_mapping_section.last_mut().unwrap().insert(*v);
+        if bone_mapping_section.len() != bone_mapping_section_count {
+            return Err(Error::InvalidValue { offset: 0x20, what: "bone palette count" });
         }
 
-        bone_map_section_to_batch.push(bone_mapping_section.len() - 1);
+        reader.set_position(0x24);
+        let bone_mapping_table_ptr = reader.u32()?;
+        reader.seek_to(bone_mapping_table_ptr as _)?;
+        
+        let mut bone_mapping_table: Vec<Vec<i32>> = Vec::new();
+        {
+            for s in &bone_mapping_section {
+                let max_value = *s.iter().max().unwrap_or(&-1);
+                if max_value + 1 != s.len() as i32 {
+                    return Err(Error::InvalidValue { offset: reader.position(), what: "bone palette" });
+                }
+                let mut subtable = Vec::new();
+                for _ in 0..s.len() {
+                    subtable.push(reader.u16()? as i32);
+                }
+                bone_mapping_table.push(subtable);
+            }
+        }
 
+        // eprintln!("{}", reader.position());
 
-        for j in 0..face_count {
-            let face_start = section_ptr + 32 + i * 64 + face_offset + j * 6;
-            reader.set_position(face_start as _);
-            let _0 = reader.read_u16::<LE>().unwrap() as u32;
-            let _1 = reader.read_u16::<LE>().unwrap() as u32;
-            let _2 = reader.read_u16::<LE>().unwrap() as u32;
-            mesh.face.push([_0, _1, _2]);
-        }
-        ktmodel.meshs.push(mesh);
-    }
-    assert_eq!(bone_mapping_section.len(), bone_mapping_section_count);
-
-    reader.set_position(0x24);
-    let bone_mapping_table_ptr = reader.read_u32::<LE>().unwrap();
-    reader.set_position(bone_mapping_table_ptr as _);
-    
-    let mut bone_mapping_table: Vec<Vec<i32>> = Vec::new();
-    {
-        for s in &bone_mapping_section {
-            let max_value = *s.iter().max().unwrap() as usize;
-            assert_eq!(max_value + 1, s.len());
-            let mut subtable = Vec::new();
-            for _ in 0..s.len() {
-                subtable.push(reader.read_u16::<LE>().unwrap() as i32);
+        for (i, m) in ktmodel.meshs.iter_mut().enumerate() {
+            let batch = bone_map_section_to_batch[i];
+            for v in &mut m.verts {
+                for j in 0..4 {
+                    v.bone_index[j] = bone_mapping_table[batch][v.bone_index[j] as usize];
+                }
             }
-            bone_mapping_table.push(subtable);
         }
+        Ok(ktmodel)
     }
 
-    // eprintln!("{}", reader.position());
-
-    let mut verts = Vec::new();
-    let mut vert_start = 0;
-    let mut faces = Vec::new();
-    let mut mats = Vec::new();
-    for i in 0..ktmodel.meshs.len() {
-        let m = &ktmodel.meshs[i];
-        let batch = bone_map_section_to_batch[i];
-        for v in &m.verts {
-            let mut bone_index = IVec4::ZERO;
-            for j in 0..4 {
-                bone_index[j] = bone_mapping_table[batch][v.bone_index[j] as usize];
+    /// The model as PMX in `.model` space. `tangents` stores the tangent and
+    /// bitangent of each vertex in the first two appendix UVs.
+    pub fn to_pmx(&self, comment: &str, tangents: bool) -> pmx::Pmx {
+        let mut verts = Vec::new();
+        let mut vert_start = 0;
+        let mut faces = Vec::new();
+        let mut mats = Vec::new();
+        for (i, m) in self.meshs.iter().enumerate() {
+            for v in &m.verts {
+                verts.push(pmx::Vertex {
+                    pos: v.pos,
+                    nrm: v.norm,
+                    uv: v.uv,
+                    appendix_uv: if tangents {
+                        [v.tang.extend(0.0), v.bitang.extend(0.0), Vec4::ZERO, Vec4::ZERO]
+                    } else {
+                        [Vec4::ZERO; 4]
+                    },
+                    weight: pmx::VertexWeight::Four(v.bone_index, v.bone_weight),
+                    edge_scale: 1.0,
+                });
             }
-
-            verts.push(pmx::Vertex {
-                pos: v.pos,
-                nrm: v.norm,
-                uv: v.uv,
-                weight: pmx::VertexWeight::Four(bone_index, v.bone_weight),
-                edge_scale: 1.0,
+            for f in &m.face {
+                faces.push([
+                    f[0] + vert_start,
+                    f[1] + vert_start,
+                    f[2] + vert_start,
+                ]);
+            }
+            vert_start += m.verts.len() as u32;
+            mats.push(pmx::Mat {
+                name: i.to_string(),
+                associated_face_count: m.face.len() as _,
+                ..Default::default()
             });
         }
-        for f in &m.face {
-            faces.push([
-                f[0] + vert_start,
-                f[1] + vert_start,
-                f[2] + vert_start,
-            ]);
+        let mut bones = Vec::new();
+        for i in 0..self.bone_names.len() {
+            bones.push(pmx::Bone {
+                name: self.bone_names[i].clone(),
+                name_en: self.bone_names[i].clone(),
+                pos: self.bone_pos[i],
+                parent_index: self.bone_parent[i],
+                ..Default::default()
+            });
         }
-        vert_start += m.verts.len() as u32;
-        let mut mat = pmx::Mat::default();
-        mat.name = i.to_string();
-        mat.associated_face_count = m.face.len() as _;
-        mats.push(mat);
+
+        let mut pmx = pmx::Pmx {
+            name: "ktmdl".to_string(),
+            name_en: "ktmdl".to_string(),
+            comment: comment.to_string(),
+            comment_en: comment.to_string(),
+            appendix_uv_count: if tangents { 2 } else { 0 },
+            verts,
+            faces,
+            texs: Vec::new(),
+            mats,
+            bones,
+            iks: Vec::new(),
+            morphs: Vec::new(),
+            display_frames: Vec::new(),
+            rigidbodys: Vec::new(),
+            joints: Vec::new(),
+        };
+        pmx.display_frames = pmx.default_display_frames();
+        pmx
     }
-    let mut bones = Vec::new();
-    for i in 0..ktmodel.bone_names.len() {
-        let mut b = pmx::Bone::default();
-        b.name = ktmodel.bone_names[i].clone();
-        b.name_en = ktmodel.bone_names[i].clone();
-        b.pos = ktmodel.bone_pos[i];
-        b.parent_index = ktmodel.bone_parent[i];
-        bones.push(b);
+
+    /// Names the bones from a parsed `.b2it`, which must list exactly one
+    /// name per bone.
+    pub fn set_bone_names(&mut self, bone_names: Vec<String>) -> Result<()> {
+        if bone_names.len() != self.bone_pos.len() {
+            return Err(Error::InvalidValue { offset: 0x18, what: "bone count (does not match .b2it)" });
+        }
+        self.bone_names = bone_names;
+        Ok(())
     }
 
-    let mut pmx_mdl = pmx::Pmx {
-        name: "ktmdl".to_string(),
-        name_en: "ktmdl".to_string(),
-        comment: save_path.to_string(),
-        comment_en: save_path.to_string(),
-        verts,
-        faces,
-        texs: Vec::new(),
-        mats,
-        bones,
-        iks: Vec::new(),
-        morphs: Vec::new(),
-        rigidbodys: Vec::new(),
-        joints: Vec::new(),
-    };
-    pmx_mdl.scale(12.5);
-    pmx_mdl.right_hand();
-    let data = pmx_mdl.write();
-    let write_path = save_path.to_string() + ".pmx";
-    std::fs::write(write_path, data).unwrap();
+    /// Bind matrix of bone `i` relative to its parent.
+    pub fn bone_local_matrix(&self, i: usize) -> Mat4 {
+        match self.bone_parent[i] {
+            Some(p) => self.bone_matrix[p].inverse() * self.bone_matrix[i],
+            None => self.bone_matrix[i],
+        }
+    }
+
+    /// Maps model space into the space of bone `i` at bind pose.
+    pub fn inverse_bind_matrix(&self, i: usize) -> Mat4 {
+        self.bone_matrix[i].inverse()
+    }
 }
 
 
 #[derive(Default, Clone, Copy)]
-struct KTVertex {
-    pos: Vec3,
-    bone_index: IVec4,
-    bone_weight: Vec4,
-    norm: Vec3,
-    tang: Vec3,
-    bitang: Vec3,
-    uv: Vec2,
+pub struct KTVertex {
+    pub pos: Vec3,
+    pub bone_index: IVec4,
+    pub bone_weight: Vec4,
+    pub norm: Vec3,
+    pub tang: Vec3,
+    pub bitang: Vec3,
+    pub uv: Vec2,
 }
 
 #[derive(Default, Clone)]
-struct KTSubMesh {
-    verts: Vec<KTVertex>,
-    face: Vec<[u32; 3]>,
+pub struct KTSubMesh {
+    pub verts: Vec<KTVertex>,
+    pub face: Vec<[u32; 3]>,
 }
 
 #[derive(Default, Clone)]
-struct KTModel {
-    bone_names: Vec<String>,
-    bone_pos: Vec<Vec3>,
-    bone_parent: Vec<Option<usize>>,
-    meshs: Vec<KTSubMesh>,
+pub struct KTModel {
+    pub bone_names: Vec<String>,
+    pub bone_pos: Vec<Vec3>,
+    pub bone_parent: Vec<Option<usize>>,
+    /// Model-space bind matrix of each bone, rows 1 to 4 of its record.
+    /// The translation column equals `bone_pos`.
+    pub bone_matrix: Vec<Mat4>,
+    pub bone_unknown: Vec<KTBoneUnknown>,
+    pub meshs: Vec<KTSubMesh>,
 }
 
-fn read_string_to_null<T>(reader: &mut T) -> String 
-    where T: BufRead {
-        let mut buf = Vec::new();
-        loop {
-            let c = reader.read_u8().unwrap();
-            if c == 0 {
-                break;
-            }
-            buf.push(c);
-        }
-        String::from_utf8(buf).unwrap()
+/// Parts of the 176-byte bone record that are not decoded yet, kept verbatim.
+#[derive(Default, Clone, Copy)]
+pub struct KTBoneUnknown {
+    pub row0: [u8; 16],
+    pub rows5_9: [[u8; 16]; 5],
+    /// Row 10 up to the parent index in its last four bytes.
+    pub row10: [u8; 12],
 }
 
-pub fn parse_b2it(data: &Vec<u8>) -> Vec<String> {
-    let mut reader = std::io::Cursor::new(data);
+
+pub fn parse_b2it(data: &[u8]) -> Result<Vec<String>> {
+    let mut reader = Cursor::new(data);
     reader.set_position(0x10);
-    let count = reader.read_u32::<LE>().unwrap();
+    let count = reader.u32()?;
     reader.set_position(0x18);
-    let offset = reader.read_u32::<LE>().unwrap();
+    let offset = reader.u32()?;
     reader.set_position(0x20);
     let mut str_starts = Vec::new();
     for _ in 0..count {
-        str_starts.push(reader.read_u32::<LE>().unwrap());
+        str_starts.push(reader.u32()?);
     }
     let mut bone_names = Vec::new();
     for str_start in &str_starts {
-        reader.set_position(*str_start as _);
-        bone_names.push(read_string_to_null(&mut reader));
+        reader.seek_to(*str_start as _)?;
+        bone_names.push(read_string_to_null(&mut reader)?);
     }
     let mut numbers = Vec::new();
-    reader.set_position(offset as _);
+    reader.seek_to(offset as _)?;
     for _ in 0..count {
-        numbers.push(reader.read_u32::<LE>().unwrap());
+        numbers.push(reader.u32()?);
     }
     let mut out = Vec::new();
     out.resize(numbers.len(), String::new());
 
-    for i in 0..count {
-        out[numbers[i as usize] as usize] = bone_names[i as usize].clone();
+    for i in 0..count as usize {
+        let Some(slot) = out.get_mut(numbers[i] as usize) else {
+            return Err(Error::InvalidValue { offset: offset as u64 + 4 * i as u64, what: "bone number" });
+        };
+        *slot = bone_names[i].clone();
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tangents_mirror_with_normals() {
+        let model = KTModel {
+            bone_names: vec!["center".to_string()],
+            bone_pos: vec![Vec3::ZERO],
+            bone_parent: vec![None],
+            bone_matrix: vec![Mat4::IDENTITY],
+            bone_unknown: vec![KTBoneUnknown::default()],
+            meshs: vec![KTSubMesh {
+                verts: vec![KTVertex {
+                    pos: vec3(0.0, 1.0, 0.5),
+                    bone_index: IVec4::ZERO,
+                    bone_weight: Vec4::X,
+                    norm: vec3(0.0, 0.6, 0.8),
+                    tang: vec3(0.6, 0.0, 0.8),
+                    bitang: vec3(0.0, 0.8, -0.6),
+                    uv: Vec2::ZERO,
+                }],
+                face: Vec::new(),
+            }],
+        };
+        let pmx = mmd_pmx(&model, "", true);
+        let v = &pmx.verts[0];
+        assert_eq!(v.pos, vec3(0.0, 12.5, -6.25));
+        assert_eq!(v.nrm, vec3(0.0, 0.6, -0.8));
+        assert_eq!(v.appendix_uv[0], vec4(0.6, 0.0, -0.8, 0.0));
+        assert_eq!(v.appendix_uv[1], vec4(0.0, 0.8, 0.6, 0.0));
+
+        let pmx = mmd_pmx(&model, "", false);
+        assert_eq!(pmx.appendix_uv_count, 0);
+        assert_eq!(pmx.verts[0].appendix_uv, [Vec4::ZERO; 4]);
     }
-    out
 }
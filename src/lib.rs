@@ -0,0 +1,14 @@
+pub mod anm;
+pub mod arc;
+pub mod batch;
+pub mod bvh;
+pub mod error;
+pub mod gltf;
+pub mod ktmdl;
+pub mod obj;
+pub mod packed_quat;
+pub mod pmx;
+pub mod pose;
+pub mod vmd;
+
+pub use error::{Error, Result};
@@ -1,111 +1,388 @@
-use std::{fs::{self, File}, io::{Cursor, BufRead, Read, Write}, num};
+use std::{error::Error, path::{Path, PathBuf}, process::ExitCode};
 
-use byteorder::{ReadBytesExt, LE, BE};
+use fuck_dance::{anm, arc::{sanitize_entry_name, ArcArchive}, batch, bvh, gltf, ktmdl, obj, pmx, vmd};
 
-mod pmx;
-mod ktmdl;
+const USAGE: &str = "usage:
+    fuck_dance list <arc>
+    fuck_dance extract <arc> [-o <dir>]
+    fuck_dance convert <arc> [-o <dir>] [-f pmx|gltf|glb|obj] [--tangents] [--utf16] [--compact]
+    fuck_dance info <file>
+    fuck_dance batch <dir> [-o <dir>] [-j <jobs>]
+    fuck_dance motion <anm> -m <model> -b <b2it> [-o <dir>] [-f vmd|bvh] [--order zxy] [--translations]
+    fuck_dance camera <anm> [-o <dir>] [--fov <degrees>]
+    fuck_dance animate <model> -b <b2it> -a <anm> [-a <anm>...] [-o <dir>]
 
-fn read_string_to_null<T>(reader: &mut T) -> String 
-    where T: BufRead {
-        let mut buf = Vec::new();
-        loop {
-            let c = reader.read_u8().unwrap();
-            if c == 0 {
-                break;
-            }
-            buf.push(c);
+sampling options for motion, camera and animate:
+    --fps <n>                     output frame rate (bvh and glb; vmd is always 30)
+    --interp step|linear|slerp    override each track's interpolation
+    --loop                        wrap the last frame back into the first
+    --reduce <degrees> <distance> drop keys rebuilt within these tolerances (vmd and glb),
+                                  distance in PMX units for vmd and metres for glb";
+
+struct Args {
+    command: String,
+    input: PathBuf,
+    output: PathBuf,
+    jobs: Option<usize>,
+    format: Option<String>,
+    model: Option<PathBuf>,
+    b2it: Option<PathBuf>,
+    fov: u32,
+    anims: Vec<PathBuf>,
+    bvh: bvh::BvhOptions,
+    sampling: anm::Sampling,
+    tolerance: Option<anm::Tolerance>,
+    tangents: bool,
+    pmx: pmx::WriteOptions,
+}
+
+fn parse_args() -> Option<Args> {
+    let mut args = std::env::args().skip(1);
+    let command = args.next()?;
+    let mut input = None;
+    let mut output = PathBuf::from(".");
+    let mut jobs = None;
+    let mut format = None;
+    let mut model = None;
+    let mut b2it = None;
+    let mut fov = 30;
+    let mut anims = Vec::new();
+    let mut bvh = bvh::BvhOptions::default();
+    let mut sampling = anm::Sampling::default();
+    let mut tolerance = None;
+    let mut tangents = false;
+    let mut pmx = pmx::WriteOptions::default();
+    while let Some(arg) = args.next() {
+        if arg == "-o" || arg == "--output" {
+            output = PathBuf::from(args.next()?);
+        } else if arg == "-j" || arg == "--jobs" {
+            jobs = Some(args.next()?.parse().ok()?);
+        } else if arg == "-f" || arg == "--format" {
+            format = Some(args.next()?);
+        } else if arg == "--order" {
+            bvh.rotation_order = args.next()?.parse().ok()?;
+        } else if arg == "--translations" {
+            bvh.translations = true;
+        } else if arg == "--fps" {
+            sampling.fps = args.next()?.parse().ok().filter(|&fps: &f32| fps > 0.0 && fps.is_finite())?;
+        } else if arg == "--interp" {
+            sampling.interpolation = Some(args.next()?.parse().ok()?);
+        } else if arg == "--loop" {
+            sampling.wrap = anm::Wrap::Loop;
+        } else if arg == "--tangents" {
+            tangents = true;
+        } else if arg == "--utf16" {
+            pmx.encoding = pmx::TextEncoding::Utf16;
+        } else if arg == "--compact" {
+            pmx.compact_indices = true;
+        } else if arg == "--reduce" {
+            let angle: f32 = args.next()?.parse().ok()?;
+            let distance: f32 = args.next()?.parse().ok()?;
+            tolerance = Some(anm::Tolerance { angle: angle.to_radians(), distance });
+        } else if arg == "-m" || arg == "--model" {
+            model = Some(PathBuf::from(args.next()?));
+        } else if arg == "-b" || arg == "--b2it" {
+            b2it = Some(PathBuf::from(args.next()?));
+        } else if arg == "--fov" {
+            fov = args.next()?.parse().ok()?;
+        } else if arg == "-a" || arg == "--anm" {
+            anims.push(PathBuf::from(args.next()?));
+        } else if input.is_none() {
+            input = Some(PathBuf::from(arg));
+        } else {
+            return None;
         }
-        String::from_utf8(buf).unwrap()
+    }
+    Some(Args {
+        command,
+        input: input?,
+        output,
+        jobs,
+        format,
+        model,
+        b2it,
+        fov,
+        anims,
+        bvh: bvh::BvhOptions { sampling, ..bvh },
+        sampling,
+        tolerance,
+        tangents,
+        pmx,
+    })
+}
+
+fn open_archive(path: &Path) -> Result<ArcArchive, Box<dyn Error>> {
+    ArcArchive::open(path).map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+fn entry_error(path: &Path, name: &str, e: fuck_dance::Error) -> String {
+    format!("{}: {}: {}", path.display(), name, e)
+}
+
+fn list(path: &Path) -> Result<(), Box<dyn Error>> {
+    let archive = open_archive(path)?;
+    println!("{:>10} {:>10} {:>10} {:>10}  name", "name_off", "offset", "size", "zsize");
+    for entry in archive.entries() {
+        println!(
+            "{:>10} {:>10} {:>10} {:>10}  {}",
+            entry.name_offset, entry.offset, entry.size, entry.zsize, entry.name
+        );
+    }
+    Ok(())
 }
 
-#[derive(Debug)]
-struct Info {
-    name_offset: u32,
-    offset: u32,
-    size: u32,
-    zsize: u32,
+fn extract(path: &Path, out_dir: &Path) -> Result<(), Box<dyn Error>> {
+    let archive = open_archive(path)?;
+    let report = archive.extract_to(out_dir)?;
+    for path in &report.written {
+        eprintln!("{}", path.display());
+    }
+    for (name, path) in &report.renamed {
+        eprintln!("renamed: {:?} -> {}", name, path.display());
+    }
+    for name in &report.rejected {
+        eprintln!("rejected: {:?}", name);
+    }
+    for (name, path) in &report.collisions {
+        eprintln!("skipped: {:?} (already extracted as {})", name, path.display());
+    }
+    Ok(())
 }
 
-fn uncompresse(data: &Vec<u8>, raw_size: u32) -> Vec<u8> {
-    let length = data.len() as u64;
-    let mut reader = Cursor::new(data);
-    let mut control_code: u16 = 0;
-    let mut output: Vec<u8> = Vec::with_capacity(raw_size as _);
-    while length - reader.position() > 0 {
-        if control_code & 0x100 == 0 {
-            control_code = reader.read_u8().unwrap() as u16;
-            control_code |= 0xFF00;
+fn convert(
+    path: &Path,
+    out_dir: &Path,
+    format: &str,
+    tangents: bool,
+    pmx: &pmx::WriteOptions,
+) -> Result<(), Box<dyn Error>> {
+    if !matches!(format, "pmx" | "gltf" | "glb" | "obj") {
+        return Err(format!("unknown output format {:?}", format).into());
+    }
+    let archive = open_archive(path)?;
+    let find = |ext: &str| -> Result<_, Box<dyn Error>> {
+        let mut found = archive.entries().filter(|e| e.name.ends_with(ext));
+        match (found.next(), found.next()) {
+            (Some(entry), None) => Ok(entry),
+            (None, _) => Err(format!("{}: no {} entry", path.display(), ext).into()),
+            (Some(_), Some(_)) => Err(format!("{}: more than one {} entry", path.display(), ext).into()),
         }
-        if control_code & 1 != 0 {
-            output.push(reader.read_u8().unwrap());
-        } else {
-            let flag = reader.read_u16::<BE>().unwrap();
-            if flag == 0 {
-                break;
+    };
+    let model_entry = find(".model")?;
+    let b2it_entry = find(".b2it")?;
+    let model = archive.read(model_entry).map_err(|e| entry_error(path, &model_entry.name, e))?;
+    let b2it = archive.read(b2it_entry).map_err(|e| entry_error(path, &b2it_entry.name, e))?;
+    let b2it = ktmdl::parse_b2it(&b2it).map_err(|e| entry_error(path, &b2it_entry.name, e))?;
+
+    let rel_path = sanitize_entry_name(&model_entry.name)
+        .ok_or_else(|| format!("{}: invalid entry name {:?}", path.display(), model_entry.name))?;
+    // Where the `.model` would be if the archive were extracted in place,
+    // which is where the OBJ export looks for textures.
+    let model_path = path.with_file_name(&rel_path);
+    let save_path = out_dir.join(rel_path);
+    if let Some(dir_path) = save_path.parent() {
+        std::fs::create_dir_all(dir_path)?;
+    }
+    if format != "pmx" {
+        let mut model = ktmdl::KTModel::read(model).map_err(|e| entry_error(path, &model_entry.name, e))?;
+        model.set_bone_names(b2it).map_err(|e| entry_error(path, &model_entry.name, e))?;
+        let save_path = PathBuf::from(format!("{}.{}", save_path.display(), format));
+        match format {
+            "glb" => gltf::from_ktmodel(&model).write_glb(&save_path)?,
+            "gltf" => gltf::from_ktmodel(&model).write_gltf(&save_path)?,
+            _ => obj::ktmodel_to_obj(&model, &save_path, &model_path)?,
+        }
+        eprintln!("{}", save_path.display());
+        return Ok(());
+    }
+    let save_path = save_path.to_str().ok_or("output path is not valid UTF-8")?;
+    ktmdl::ktmodel_to_pmx(model, b2it, save_path, tangents, pmx).map_err(|e| entry_error(path, &model_entry.name, e))?;
+    eprintln!("{}.pmx", save_path);
+    Ok(())
+}
+
+fn info(path: &Path) -> Result<(), Box<dyn Error>> {
+    let content = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let ctx = |e: fuck_dance::Error| format!("{}: {}", path.display(), e);
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("model") => {
+            let model = ktmdl::KTModel::read(content).map_err(ctx)?;
+            println!("bones: {}", model.bone_pos.len());
+            println!("submeshes: {}", model.meshs.len());
+            for (i, m) in model.meshs.iter().enumerate() {
+                println!("  {}: {} verts, {} faces", i, m.verts.len(), m.face.len());
             }
-            let offset = flag >> 4;
-            let len = (flag & 0xF) + 3;
-            for _ in 0..len {
-                let pos = output.len() as i32 - offset as i32;
-                if pos < 0 {
-                    output.push(0);
-                } else {
-                    output.push(output[pos as usize]);
+        },
+        Some("anm") => {
+            let anm = anm::Animation::read(content).map_err(ctx)?;
+            println!("frames: {}", anm.frame_count());
+            println!("bones: {}", anm.bones.len());
+            for (i, b) in anm.bones.iter().enumerate() {
+                let describe = |kind: u16, sparse: bool, keys: usize| {
+                    format!("type {} {} {} keys", kind, if sparse { "sparse" } else { "dense" }, keys)
+                };
+                let rot = b.rotation.as_ref().map(|t| describe(t.kind, t.is_sparse(), t.keys.len()));
+                let trans = b.translation.as_ref().map(|t| describe(t.kind, t.is_sparse(), t.keys.len()));
+                if rot.is_some() || trans.is_some() {
+                    println!(
+                        "  {}: rotation: {}, translation: {}",
+                        i, rot.as_deref().unwrap_or("-"), trans.as_deref().unwrap_or("-")
+                    );
                 }
             }
+        },
+        Some("b2it") => {
+            let names = ktmdl::parse_b2it(&content).map_err(ctx)?;
+            println!("bones: {}", names.len());
+            for (i, name) in names.iter().enumerate() {
+                println!("  {}: {}", i, name);
+            }
+        },
+        Some("pmx") => {
+            let pmx = pmx::Pmx::read(content).map_err(ctx)?;
+            println!("name: {}", pmx.name);
+            println!("verts: {}", pmx.verts.len());
+            println!("faces: {}", pmx.faces.len());
+            println!("textures: {}", pmx.texs.len());
+            println!("materials: {}", pmx.mats.len());
+            println!("bones: {}", pmx.bones.len());
+            println!("morphs: {}", pmx.morphs.len());
+            println!("rigidbodys: {}", pmx.rigidbodys.len());
+            println!("joints: {}", pmx.joints.len());
+        },
+        _ => return Err(format!("{}: expected a .model, .anm, .b2it or .pmx file", path.display()).into()),
+    }
+    Ok(())
+}
+
+fn batch(in_dir: &Path, out_dir: &Path, jobs: Option<usize>) -> Result<(), Box<dyn Error>> {
+    let jobs = jobs.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    let report = batch::convert_dir(in_dir, out_dir, jobs)
+        .map_err(|e| format!("{}: {}", in_dir.display(), e))?;
+    let summary = report.summary();
+    std::fs::create_dir_all(out_dir)?;
+    let report_path = out_dir.join("batch_report.txt");
+    std::fs::write(&report_path, &summary)?;
+    eprint!("{}", summary);
+    eprintln!("report: {}", report_path.display());
+    if report.failed() > 0 {
+        return Err(format!("{} conversions failed", report.failed()).into());
+    }
+    Ok(())
+}
+
+fn print_reductions(reductions: &[anm::Reduction]) {
+    for r in reductions {
+        eprintln!("  {}: {} -> {} keys ({:.1}x)", r.track, r.before, r.after, r.ratio());
+    }
+    let before: usize = reductions.iter().map(|r| r.before).sum();
+    let after: usize = reductions.iter().map(|r| r.after).sum();
+    let total = anm::Reduction { track: "total".to_string(), before, after };
+    eprintln!("  total: {} -> {} keys ({:.1}x)", before, after, total.ratio());
+}
+
+fn motion(args: &Args) -> Result<(), Box<dyn Error>> {
+    let (path, out_dir) = (args.input.as_path(), args.output.as_path());
+    let (Some(model_path), Some(b2it_path)) = (args.model.as_deref(), args.b2it.as_deref()) else {
+        return Err("motion needs both -m <model> and -b <b2it>".into());
+    };
+    let format = args.format.as_deref().unwrap_or("vmd");
+    if !matches!(format, "vmd" | "bvh") {
+        return Err(format!("unknown motion format {:?}", format).into());
+    }
+    let read = |p: &Path| std::fs::read(p).map_err(|e| format!("{}: {}", p.display(), e));
+    let ctx = |p: &Path, e: fuck_dance::Error| format!("{}: {}", p.display(), e);
+    let anm = anm::Animation::read(read(path)?).map_err(|e| ctx(path, e))?;
+    let mut model = ktmdl::KTModel::read(read(model_path)?).map_err(|e| ctx(model_path, e))?;
+    model.bone_names = ktmdl::parse_b2it(&read(b2it_path)?).map_err(|e| ctx(b2it_path, e))?;
+    let data = if format == "bvh" {
+        bvh::anm_to_bvh(&model, &anm, &args.bvh).map_err(|e| ctx(path, e))?.into_bytes()
+    } else {
+        let mut vmd = vmd::anm_to_vmd(&model, &anm, &args.sampling).map_err(|e| ctx(path, e))?;
+        if let Some(tolerance) = &args.tolerance {
+            print_reductions(&vmd.reduce(tolerance));
         }
-        control_code >>= 1;
+        vmd.write()
+    };
+
+    let stem = path.file_stem().ok_or_else(|| format!("{}: no file name", path.display()))?;
+    std::fs::create_dir_all(out_dir)?;
+    let save_path = out_dir.join(stem).with_extension(format);
+    std::fs::write(&save_path, data)?;
+    eprintln!("{}", save_path.display());
+    Ok(())
+}
+
+fn camera(args: &Args) -> Result<(), Box<dyn Error>> {
+    let (path, out_dir) = (args.input.as_path(), args.output.as_path());
+    let content = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let camera = anm::Camera::read(content).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut vmd = vmd::camera_to_vmd(&camera, args.fov, &args.sampling).map_err(|e| format!("{}: {}", path.display(), e))?;
+    if let Some(tolerance) = &args.tolerance {
+        print_reductions(&vmd.reduce(tolerance));
     }
-    assert_eq!(output.len(), raw_size as usize);
-    output
+
+    let stem = path.file_stem().ok_or_else(|| format!("{}: no file name", path.display()))?;
+    std::fs::create_dir_all(out_dir)?;
+    let save_path = out_dir.join(stem).with_extension("vmd");
+    std::fs::write(&save_path, vmd.write())?;
+    eprintln!("{}", save_path.display());
+    Ok(())
 }
 
-fn main() {
-    let content = fs::read("model_pl_unaf000.arc").unwrap();
-    let mut reader = Cursor::new(content);
-    let _magic = reader.read_u32::<LE>().unwrap();
-    let _version = reader.read_u32::<LE>().unwrap();
-    let files = reader.read_u32::<LE>().unwrap();
-    let _ = reader.read_u32::<LE>().unwrap();
-    let mut infos = Vec::new();
-    for _ in 0..files {
-        infos.push(Info {
-            name_offset: reader.read_u32::<LE>().unwrap(),
-            offset: reader.read_u32::<LE>().unwrap(),
-            size: reader.read_u32::<LE>().unwrap(),
-            zsize: reader.read_u32::<LE>().unwrap(),
-        });
-    }
-    let mut model: Vec<u8> = Vec::new();
-    let mut b2it: Vec<String> = Vec::new();
-    let mut save_path = String::new();
-    for info in &infos {
-        reader.set_position(info.name_offset as _);
-        let name = read_string_to_null(&mut reader);
-        eprintln!("{}: {:?}", name, info);
-
-        reader.set_position(info.offset as _);
-        let mut compressed = vec![0u8; info.zsize as _];
-        reader.read_exact(&mut compressed).unwrap();
-        let uncompressed = if info.size == info.zsize {
-            compressed
-        } else {
-            uncompresse(&compressed, info.size)
-        };
-        let path = std::path::Path::new(&name);
-        let dir_path = path.parent().unwrap();
-        std::fs::create_dir_all(dir_path).unwrap();
-        std::fs::write(&name, &uncompressed).unwrap();
-        if name.ends_with(".b2it") {
-            assert_eq!(b2it.len(), 0);
-            b2it = ktmdl::parse_b2it(&uncompressed);
-        } else if name.ends_with(".model") {
-            assert_eq!(model.len(), 0);
-            model = uncompressed.clone();
-            save_path = name;
+fn animate(args: &Args) -> Result<(), Box<dyn Error>> {
+    let (path, out_dir) = (args.input.as_path(), args.output.as_path());
+    let Some(b2it_path) = args.b2it.as_deref() else {
+        return Err("animate needs -b <b2it>".into());
+    };
+    let read = |p: &Path| std::fs::read(p).map_err(|e| format!("{}: {}", p.display(), e));
+    let ctx = |p: &Path, e: fuck_dance::Error| format!("{}: {}", p.display(), e);
+    let mut model = ktmdl::KTModel::read(read(path)?).map_err(|e| ctx(path, e))?;
+    model.bone_names = ktmdl::parse_b2it(&read(b2it_path)?).map_err(|e| ctx(b2it_path, e))?;
+    let mut gltf = gltf::from_ktmodel(&model);
+    for anm_path in &args.anims {
+        let anm = anm::Animation::read(read(anm_path)?).map_err(|e| ctx(anm_path, e))?;
+        let name = anm_path.file_stem().and_then(|s| s.to_str()).unwrap_or("anm");
+        let reductions = gltf.add_animation(&model, name, &anm, &args.sampling, args.tolerance.as_ref())
+            .map_err(|e| ctx(anm_path, e))?;
+        if args.tolerance.is_some() {
+            eprintln!("{}:", name);
+            print_reductions(&reductions);
         }
     }
-    ktmdl::ktmodel_to_pmx(model, b2it, &save_path)
 
+    let stem = path.file_stem().ok_or_else(|| format!("{}: no file name", path.display()))?;
+    std::fs::create_dir_all(out_dir)?;
+    let save_path = out_dir.join(stem).with_extension("glb");
+    gltf.write_glb(&save_path)?;
+    eprintln!("{}", save_path.display());
+    Ok(())
 }
 
+fn main() -> ExitCode {
+    let Some(args) = parse_args() else {
+        eprintln!("{}", USAGE);
+        return ExitCode::from(2);
+    };
+    let result = match args.command.as_str() {
+        "list" => list(&args.input),
+        "extract" => extract(&args.input, &args.output),
+        "convert" => convert(&args.input, &args.output, args.format.as_deref().unwrap_or("pmx"), args.tangents, &args.pmx),
+        "info" => info(&args.input),
+        "batch" => batch(&args.input, &args.output, args.jobs),
+        "motion" => motion(&args),
+        "camera" => camera(&args),
+        "animate" => animate(&args),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        },
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        },
+    }
+}
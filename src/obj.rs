@@ -0,0 +1,149 @@
+use std::{fmt::Write as _, path::Path};
+
+use crate::error::Result;
+use crate::ktmdl::KTModel;
+use crate::pmx::Pmx;
+
+const TEXTURE_EXTS: [&str; 5] = ["png", "dds", "tga", "bmp", "jpg"];
+
+/// OBJ and MTL names end at the first whitespace.
+fn obj_name(name: &str, fallback: usize) -> String {
+    let name: String = name.split_whitespace().collect::<Vec<_>>().join("_");
+    if name.is_empty() { format!("mat_{}", fallback) } else { name }
+}
+
+/// `obj_name` of every material, with `_<i>` added where two would
+/// otherwise clash and share one `newmtl`.
+fn obj_names(pmx: &Pmx) -> Vec<String> {
+    let mut used = std::collections::HashSet::new();
+    pmx.mats.iter().enumerate().map(|(i, m)| {
+        let mut name = obj_name(&m.name, i);
+        let mut suffix = i;
+        while !used.insert(name.clone()) {
+            name = format!("{}_{}", obj_name(&m.name, i), suffix);
+            suffix += 1;
+        }
+        name
+    }).collect()
+}
+
+/// Writes `pmx` to `path` and its materials to a `.mtl` with the same stem.
+/// `textures[i]` is the `map_Kd` of material `i`, relative to `path`.
+fn write(pmx: &Pmx, path: &Path, textures: &[Option<String>]) -> Result<()> {
+    let mtl_path = path.with_extension("mtl");
+    let mtl_name = mtl_path.file_name().and_then(|n| n.to_str()).unwrap_or("model.mtl");
+
+    let mut obj = String::new();
+    writeln!(obj, "# {}", pmx.name).unwrap();
+    writeln!(obj, "mtllib {}", mtl_name).unwrap();
+    for v in &pmx.verts {
+        writeln!(obj, "v {} {} {}", v.pos.x, v.pos.y, v.pos.z).unwrap();
+    }
+    for v in &pmx.verts {
+        // PMX puts the UV origin at the top left, OBJ at the bottom left.
+        writeln!(obj, "vt {} {}", v.uv.x, 1.0 - v.uv.y).unwrap();
+    }
+    for v in &pmx.verts {
+        writeln!(obj, "vn {} {} {}", v.nrm.x, v.nrm.y, v.nrm.z).unwrap();
+    }
+
+    let mut mtl = String::new();
+    let mut face_start = 0;
+    for (i, (m, name)) in pmx.mats.iter().zip(obj_names(pmx)).enumerate() {
+        writeln!(obj, "g {}", name).unwrap();
+        writeln!(obj, "usemtl {}", name).unwrap();
+        let face_end = (face_start + m.associated_face_count as usize).min(pmx.faces.len());
+        for f in &pmx.faces[face_start..face_end] {
+            let [a, b, c] = f.map(|i| i + 1);
+            writeln!(obj, "f {a}/{a}/{a} {b}/{b}/{b} {c}/{c}/{c}").unwrap();
+        }
+        face_start = face_end;
+
+        writeln!(mtl, "newmtl {}", name).unwrap();
+        writeln!(mtl, "Ka {} {} {}", m.ambient.x, m.ambient.y, m.ambient.z).unwrap();
+        writeln!(mtl, "Kd {} {} {}", m.diffuse.x, m.diffuse.y, m.diffuse.z).unwrap();
+        writeln!(mtl, "Ks {} {} {}", m.specular.x, m.specular.y, m.specular.z).unwrap();
+        writeln!(mtl, "Ns {}", m.specular_strength).unwrap();
+        writeln!(mtl, "d {}", m.diffuse.w).unwrap();
+        if let Some(Some(tex)) = textures.get(i) {
+            writeln!(mtl, "map_Kd {}", tex).unwrap();
+        }
+        writeln!(mtl).unwrap();
+    }
+
+    std::fs::write(&mtl_path, mtl)?;
+    std::fs::write(path, obj)?;
+    Ok(())
+}
+
+/// Writes the static mesh of `pmx` as OBJ. Texture paths are taken from the
+/// PMX and only referenced when the file exists relative to `path`.
+pub fn pmx_to_obj(pmx: &Pmx, path: &Path) -> Result<()> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let textures: Vec<_> = pmx.mats.iter().map(|m| {
+        let tex = pmx.texs.get(usize::try_from(m.tex_index).ok()?)?.replace('\\', "/");
+        dir.join(&tex).is_file().then_some(tex)
+    }).collect();
+    write(pmx, path, &textures)
+}
+
+/// Writes the static mesh of `model` as OBJ, scaled and mirrored the way
+/// `ktmodel_to_pmx` does so the two outputs line up. `.model` files carry
+/// no texture names, so material `i` picks up `<i>.<ext>` or
+/// `<model stem>_<i>.<ext>` from beside `model_path`, the source `.model`,
+/// if one exists. Textures outside the directory of `path` are referenced
+/// by absolute path.
+pub fn ktmodel_to_obj(model: &KTModel, path: &Path, model_path: &Path) -> Result<()> {
+    let mut pmx_mdl = model.to_pmx("", false);
+    pmx_mdl.scale(12.5);
+    pmx_mdl.right_hand();
+
+    let tex_dir = std::path::absolute(model_path.parent().unwrap_or(Path::new("")))?;
+    let obj_dir = std::path::absolute(path.parent().unwrap_or(Path::new("")))?;
+    let stem = model_path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    let textures: Vec<_> = pmx_mdl.mats.iter().map(|m| {
+        let candidates = [m.name.clone(), format!("{}_{}", stem, m.name)];
+        let name = candidates.iter()
+            .flat_map(|c| TEXTURE_EXTS.iter().map(move |ext| format!("{}.{}", c, ext)))
+            .find(|name| tex_dir.join(name).is_file())?;
+        Some(if tex_dir == obj_dir { name } else { tex_dir.join(name).to_string_lossy().into_owned() })
+    }).collect();
+    write(&pmx_mdl, path, &textures)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ktmdl::{KTSubMesh, KTVertex};
+    use crate::pmx::Mat;
+    use glam::*;
+
+    #[test]
+    fn material_names_are_unique() {
+        let mut pmx = KTModel::default().to_pmx("", false);
+        pmx.mats = ["a b", "a_b", "a_b", "", "mat_3"].iter()
+            .map(|n| Mat { name: n.to_string(), ..Default::default() })
+            .collect();
+        assert_eq!(obj_names(&pmx), ["a_b", "a_b_1", "a_b_2", "mat_3", "mat_3_4"]);
+    }
+
+    #[test]
+    fn textures_are_found_beside_the_model() {
+        let root = std::env::temp_dir().join(format!("fuck_dance_obj_{}", std::process::id()));
+        let (src, out) = (root.join("src"), root.join("out"));
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::create_dir_all(&out).unwrap();
+        std::fs::write(src.join("body_0.png"), b"").unwrap();
+        std::fs::write(out.join("1.png"), b"").unwrap();
+
+        let vertex = KTVertex { pos: Vec3::ZERO, bone_index: IVec4::ZERO, bone_weight: Vec4::X, norm: Vec3::Y, tang: Vec3::X, bitang: Vec3::Z, uv: Vec2::ZERO };
+        let mesh = KTSubMesh { verts: vec![vertex; 3], face: vec![[0, 1, 2]] };
+        let model = KTModel { meshs: vec![mesh.clone(), mesh], ..Default::default() };
+        ktmodel_to_obj(&model, &out.join("body.obj"), &src.join("body.model")).unwrap();
+        let mtl = std::fs::read_to_string(out.join("body.mtl")).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let maps: Vec<&str> = mtl.lines().filter_map(|l| l.strip_prefix("map_Kd ")).collect();
+        assert_eq!(maps, [std::path::absolute(src.join("body_0.png")).unwrap().to_string_lossy()]);
+    }
+}
@@ -0,0 +1,214 @@
+//! The 48-bit rotation format of `.anm` tracks (`getQuat` in `Dance.cpp`).
+//!
+//! Read as a little-endian integer, bits 0-1 give the index (x, y, z, w) of
+//! the component that is left out, and bits 2-16, 17-31 and 32-46 hold the
+//! other three, last to first, as `(k - 16383.5) / SCALE`. The left-out
+//! component is the largest in magnitude and is rebuilt as the positive
+//! square root, so the stored three always lie in `[-1/√2, 1/√2]`.
+//!
+//! Error bounds for unit input, with `δ = 0.5 / SCALE ≈ 2.16e-5`:
+//! each stored component is off by at most `δ`, the rebuilt one by at most
+//! `3δ` because it is at least 1/2, and the decoded rotation differs from the
+//! encoded one by at most [`MAX_ANGLE_ERROR`] radians.
+
+use glam::*;
+
+/// Fixed-point scale, `32767 / √2` rounded to `f32`.
+pub const SCALE: f32 = 23169.768;
+
+/// Largest error of a stored component, half a quantisation step.
+pub const MAX_COMPONENT_ERROR: f32 = 0.5 / SCALE;
+
+/// Largest rotation angle between a unit quaternion and its round trip.
+/// `2·√12·δ ≈ 1.5e-4` is the angle for `q` off by `δ` in three components
+/// and `3δ` in the fourth; this rounds it up.
+pub const MAX_ANGLE_ERROR: f32 = 1.6e-4;
+
+const MID: f32 = 16383.5;
+
+/// Decodes six bytes into a unit quaternion whose left-out component is
+/// non-negative. Never fails: every bit pattern decodes to something,
+/// though bit 47 is ignored and a triple longer than one is normalised
+/// with the left-out component set to zero.
+pub fn decode(data: [u8; 6]) -> Quat {
+    let mut num = 0u64;
+    for &b in data.iter().rev() {
+        num = num << 8 | b as u64;
+    }
+    let component = |n: u64| ((n & 0x7FFF) as f32 - MID) / SCALE;
+    let largest = (num & 3) as usize;
+    let c0 = component(num >> 32);
+    let c1 = component(num >> 17);
+    let c2 = component(num >> 2);
+    let len2 = c0 * c0 + c1 * c1 + c2 * c2;
+    let w = (1.0 - len2).max(0.0).sqrt();
+    let mut out = [0.0; 4];
+    let mut rest = [c0, c1, c2].into_iter();
+    for (i, slot) in out.iter_mut().enumerate() {
+        *slot = if i == largest { w } else { rest.next().unwrap() };
+    }
+    let q = Quat::from_array(out);
+    if len2 > 1.0 { q.normalize() } else { q }
+}
+
+/// Encodes `q`, normalising it first. `q` and `-q` encode the same way.
+/// A zero or non-finite quaternion encodes as identity. Re-encoding a
+/// decoded value gives back the same bytes unless its two largest
+/// components are within a quantisation step of each other.
+pub fn encode(q: Quat) -> [u8; 6] {
+    let q = if q.is_finite() && q.length_squared() > 0.0 { q.normalize() } else { Quat::IDENTITY };
+    let mut v = q.to_array();
+    let mut largest = 0;
+    for i in 1..4 {
+        if v[i].abs() > v[largest].abs() {
+            largest = i;
+        }
+    }
+    if v[largest] < 0.0 {
+        v = v.map(|c| -c);
+    }
+    let mut num = largest as u64;
+    let mut shift = 32;
+    for (i, c) in v.into_iter().enumerate() {
+        if i == largest {
+            continue;
+        }
+        let k = (c * SCALE + MID).round().clamp(0.0, 0x7FFF as f32) as u64;
+        num |= k << shift;
+        shift -= 15;
+    }
+    let mut data = [0u8; 6];
+    for (i, b) in data.iter_mut().enumerate() {
+        *b = (num >> (8 * i)) as u8;
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rotation angle between two quaternions, from the chord between them
+    /// in f64 so that nearly equal inputs do not lose precision in `acos`.
+    fn angle(a: Quat, b: Quat) -> f64 {
+        let a = a.to_array().map(f64::from);
+        let b = b.to_array().map(f64::from);
+        let len = |q: [f64; 4]| q.iter().map(|c| c * c).sum::<f64>().sqrt();
+        let (la, lb) = (len(a), len(b));
+        let dot: f64 = a.iter().zip(&b).map(|(x, y)| x * y).sum();
+        let sign = if dot < 0.0 { -1.0 } else { 1.0 };
+        let chord = a.iter().zip(&b).map(|(x, y)| (x / la - sign * y / lb).powi(2)).sum::<f64>().sqrt();
+        4.0 * (chord / 2.0).asin()
+    }
+
+    /// Checks the documented error bounds for `q`, which must be unit
+    /// length. f32 rounding in `decode` may add a few ulps on top.
+    fn check_round_trip(q: Quat) {
+        let d = decode(encode(q));
+        let mut rebuilt = 0;
+        for i in 1..4 {
+            if q.to_array()[i].abs() > q.to_array()[rebuilt].abs() {
+                rebuilt = i;
+            }
+        }
+        let q = if q.to_array()[rebuilt] < 0.0 { -q } else { q };
+        let slack = 4.0 * f32::EPSILON;
+        for i in 0..4 {
+            let bound = if i == rebuilt { 3.0 * MAX_COMPONENT_ERROR } else { MAX_COMPONENT_ERROR };
+            let error = (q.to_array()[i] - d.to_array()[i]).abs();
+            assert!(error <= bound + slack, "{:?} -> {:?}: component {} off by {}", q, d, i, error);
+        }
+        assert!(angle(q, d) <= MAX_ANGLE_ERROR as f64, "{:?} -> {:?}: angle {}", q, d, angle(q, d));
+    }
+
+    /// Deterministic values in `[-1, 1)`.
+    fn noise(seed: &mut u64) -> f32 {
+        *seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        ((*seed >> 40) as f32 / (1u64 << 23) as f32) * 2.0 - 1.0
+    }
+
+    #[test]
+    fn round_trip_each_largest_component() {
+        let mut seed = 1;
+        for largest in 0..4 {
+            for _ in 0..10_000 {
+                let mut v = [0.0; 4].map(|_: f32| noise(&mut seed));
+                let m = v.iter().map(|c| c.abs()).fold(0.0, f32::max);
+                v[largest] = (m + 0.01).copysign(v[largest]);
+                let q = Quat::from_array(v).normalize();
+                check_round_trip(q);
+                assert_eq!(encode(q)[0] & 3, largest as u8);
+            }
+        }
+    }
+
+    #[test]
+    fn sign_flip_encodes_the_same() {
+        let mut seed = 2;
+        for _ in 0..1_000 {
+            let q = Quat::from_array([0.0; 4].map(|_: f32| noise(&mut seed))).normalize();
+            assert_eq!(encode(q), encode(-q));
+            check_round_trip(-q);
+        }
+    }
+
+    #[test]
+    fn identity() {
+        let d = decode(encode(Quat::IDENTITY));
+        check_round_trip(Quat::IDENTITY);
+        assert_eq!(encode(Quat::IDENTITY)[0] & 3, 3);
+        assert!(d.w > 1.0 - 1e-6);
+    }
+
+    #[test]
+    fn near_ties() {
+        for eps in [0.0, 1e-7, MAX_COMPONENT_ERROR, 2.0 * MAX_COMPONENT_ERROR, 1e-3] {
+            for (a, b) in [(0, 1), (1, 2), (2, 3), (0, 3)] {
+                let mut v = [0.1, -0.2, 0.15, 0.05];
+                v[a] = 0.6 + eps;
+                v[b] = -0.6;
+                let q = Quat::from_array(v).normalize();
+                check_round_trip(q);
+                check_round_trip(decode(encode(q)).normalize());
+            }
+        }
+        let half = Quat::from_xyzw(0.5, 0.5, 0.5, 0.5);
+        check_round_trip(half);
+    }
+
+    #[test]
+    fn zero_and_nan_encode_as_identity() {
+        let identity = encode(Quat::IDENTITY);
+        assert_eq!(encode(Quat::from_xyzw(0.0, 0.0, 0.0, 0.0)), identity);
+        assert_eq!(encode(Quat::from_xyzw(f32::NAN, 0.0, 0.0, 1.0)), identity);
+        assert_eq!(encode(Quat::from_xyzw(f32::INFINITY, 0.0, 0.0, 1.0)), identity);
+    }
+
+    /// Expected values worked through `getQuat` by hand: each stored
+    /// 15-bit `k` gives `(k - 16383.5) / 23169.767578125` and the left-out
+    /// component is the square root of what remains.
+    #[test]
+    fn decode_matches_get_quat() {
+        let cases: [([u8; 6], [f32; 4]); 5] = [
+            // largest x; k = 20000, 12000, 8000
+            ([0x00, 0x7D, 0xC0, 0x5D, 0x20, 0x4E], [0.899402, 0.156087, -0.189191, -0.361829]),
+            // largest y; k = 30000, 5000, 16000
+            ([0x01, 0xFA, 0x10, 0x27, 0x30, 0x75], [0.587684, 0.642627, -0.491308, -0.016552]),
+            // largest z; k = 4000, 28000, 21000
+            ([0x22, 0x48, 0xC1, 0xDA, 0xA0, 0x0F], [-0.534468, 0.501365, 0.650598, 0.199247]),
+            // largest w; k = 24000, 10000, 18000
+            ([0x43, 0x19, 0x21, 0x4E, 0xC0, 0x5D], [0.328726, -0.275510, 0.069768, 0.900648]),
+            // as above with bit 47 set, which `getQuat` also ignores
+            ([0x43, 0x19, 0x21, 0x4E, 0xC0, 0xDD], [0.328726, -0.275510, 0.069768, 0.900648]),
+        ];
+        for (data, expected) in cases {
+            let q = decode(data).to_array();
+            for i in 0..4 {
+                assert!((q[i] - expected[i]).abs() < 1e-6, "{:02X?}: {:?} != {:?}", data, q, expected);
+            }
+            let mut canonical = data;
+            canonical[5] &= 0x7F;
+            assert_eq!(encode(decode(data)), canonical);
+        }
+    }
+}
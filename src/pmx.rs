@@ -1,10 +1,12 @@
 use std::io::prelude::*;
 use std::io::*;
 
-use byteorder::{LE, ReadBytesExt, WriteBytesExt};
+use byteorder::{LE, WriteBytesExt};
 use glam::*;
 use bitflags::bitflags;
 
+use crate::error::{ByteReader, Error, Result};
+
 
 #[derive(Clone)]
 pub struct Pmx {
@@ -12,6 +14,8 @@ pub struct Pmx {
     pub name_en: String,
     pub comment: String,
     pub comment_en: String,
+    /// How many of `Vertex::appendix_uv` are in use, up to 4.
+    pub appendix_uv_count: u8,
     pub verts: Vec<Vertex>,
     pub faces: Vec<[u32; 3]>,
     pub texs: Vec<String>,
@@ -19,6 +23,9 @@ pub struct Pmx {
     pub bones: Vec<Bone>,
     pub iks: Vec<Ik>,
     pub morphs: Vec<MorphInfo>,
+    /// Bone and morph groups shown in the editor, written back as they are.
+    /// See [`Pmx::default_display_frames`] for a model built from scratch.
+    pub display_frames: Vec<DisplayFrame>,
     pub rigidbodys: Vec<Rigidbody>,
     pub joints: Vec<Joint>,
 }
@@ -28,6 +35,9 @@ pub struct Vertex {
     pub pos: Vec3,
     pub nrm: Vec3,
     pub uv: Vec2,
+    /// Additional UV channels; only the first `Pmx::appendix_uv_count` are
+    /// read and written, the rest stay zero.
+    pub appendix_uv: [Vec4; 4],
     pub weight: VertexWeight,
     pub edge_scale: f32,
 }
@@ -157,7 +167,7 @@ impl Default for Bone {
             pos: Default::default(),
             parent_index: Default::default(),
             layer: Default::default(),
-            bone_flags: BoneFlags::empty(),
+            bone_flags: BoneFlags::ROTATABLE | BoneFlags::TRANSLATABLE | BoneFlags::VISIBLE | BoneFlags::ENABLED,
             bone_tail_pos: BoneTailPos::Pos(Vec3::ZERO),
             inherit: Default::default(),
             fixed_axis: Default::default(),
@@ -182,7 +192,7 @@ pub struct IkJoint {
     pub limit: Option<(Vec3, Vec3)>,
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub struct Joint {
     pub name: String,
     pub name_en: String,
@@ -199,21 +209,21 @@ pub struct Joint {
     pub rot_spring: Vec3,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone)]
 pub enum RigidbodyShape {
     Shpere,
     Box,
     Capsule,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone)]
 pub enum RigidbodyMode {
     Kinematics,
     Dynamics,
     DynamicsPassRotation,
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub struct Rigidbody {
     pub name: String,
     pub name_en: String,
@@ -246,15 +256,18 @@ pub struct DisplayFrame {
     pub morph_items: Vec<DisplayFrameIndex>,
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub struct MorphInfo {
     pub name: String,
     pub name_en: String,
     pub panel: i8,
+    /// Raw morph type. It tells which UV channel a `Morph::Uv` moves
+    /// (3 to 7); for other morphs `Pmx::write` derives it from `morph`.
     pub category: i8,
+    pub morph: Morph,
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub enum Morph {
     Group(Vec<MorphGroupItem>),
     Flip(Vec<MorphFlipItem>),
@@ -265,38 +278,38 @@ pub enum Morph {
     Mat(Vec<MorphMatItem>),
 }
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone)]
 pub struct MorphGroupItem {
     pub index: u32,
     pub affect: f32,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone)]
 pub struct MorphFlipItem {
     pub index: u32,
     pub affect: f32,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone)]
 pub struct MorphVertexItem {
     pub index: u32,
     pub trans: Vec3,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone)]
 pub struct MorphBoneItem {
     pub index: u32,
     pub trans: Vec3,
     pub rot: Vec4,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone)]
 pub struct MorphUvItem {
     pub index: u32,
     pub trans: Vec4,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone)]
 pub struct MorphRigidbodyItem {
     pub index: u32,
     pub local: bool,
@@ -304,7 +317,7 @@ pub struct MorphRigidbodyItem {
     pub rot_torque: Vec3,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone)]
 pub struct MorphMatItem {
     pub index: u32,
     pub blend_mode: BlendMode,
@@ -319,92 +332,179 @@ pub struct MorphMatItem {
     pub toon_tint: Vec4,
 }
 
-pub fn read_vec2f(file: &mut Cursor<Vec<u8>>) -> Vec2 {
-    Vec2::new(
-        file.read_f32::<LE>().unwrap(),
-        file.read_f32::<LE>().unwrap()
-    )
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TextEncoding {
+    Utf16,
+    #[default]
+    Utf8,
 }
 
-pub fn read_vec3f(file: &mut Cursor<Vec<u8>>) -> Vec3 {
-    Vec3::new(
-        file.read_f32::<LE>().unwrap(),
-        file.read_f32::<LE>().unwrap(),
-        file.read_f32::<LE>().unwrap()
-    )
+/// Options for [`Pmx::write_with`]. The default matches [`Pmx::write`]:
+/// UTF-8 text and 4-byte indices throughout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WriteOptions {
+    pub encoding: TextEncoding,
+    /// Use the smallest index size each table needs: vertex indices are
+    /// unsigned, so 1 byte covers 256 vertices, while the other indices are
+    /// signed to leave room for -1, so 1 byte covers 128 entries.
+    pub compact_indices: bool,
 }
 
-pub fn read_vec4f(file: &mut Cursor<Vec<u8>>) -> Vec4 {
-    Vec4::new(
-        file.read_f32::<LE>().unwrap(),
-        file.read_f32::<LE>().unwrap(),
-        file.read_f32::<LE>().unwrap(),
-        file.read_f32::<LE>().unwrap()
-    )
+/// Smallest unsigned index size for `count` vertices.
+fn vertex_index_size(count: usize) -> u8 {
+    if count <= u8::MAX as usize + 1 {
+        1
+    } else if count <= u16::MAX as usize + 1 {
+        2
+    } else {
+        4
+    }
+}
+
+/// Smallest signed index size for a table of `count` entries.
+fn index_size(count: usize) -> u8 {
+    if count <= i8::MAX as usize + 1 {
+        1
+    } else if count <= i16::MAX as usize + 1 {
+        2
+    } else {
+        4
+    }
+}
+
+pub fn read_vec2f(file: &mut Cursor<Vec<u8>>) -> Result<Vec2> {
+    Ok(Vec2::new(
+        file.f32()?,
+        file.f32()?
+    ))
+}
+
+pub fn read_vec3f(file: &mut Cursor<Vec<u8>>) -> Result<Vec3> {
+    Ok(Vec3::new(
+        file.f32()?,
+        file.f32()?,
+        file.f32()?
+    ))
+}
+
+pub fn read_vec4f(file: &mut Cursor<Vec<u8>>) -> Result<Vec4> {
+    Ok(Vec4::new(
+        file.f32()?,
+        file.f32()?,
+        file.f32()?,
+        file.f32()?
+    ))
 }
 
 impl Pmx {
-    fn read_string(file: &mut Cursor<Vec<u8>>, utf8: bool) -> String {
-        let len = file.read_i32::<LE>().unwrap() as usize;
-        if len == 0 {
-            return String::new();
+    fn read_string(file: &mut Cursor<Vec<u8>>, utf8: bool) -> Result<String> {
+        let offset = file.position();
+        let len = file.i32()?;
+        if len <= 0 {
+            return Ok(String::new());
         };
-        let mut content = vec![0u8; len];
-        file.read_exact(&mut content).unwrap();
-        if utf8 {
-            String::from_utf8(content).unwrap()
+        let content = file.read_bytes(len as usize)?;
+        let s = if utf8 {
+            String::from_utf8(content).ok()
+        } else if content.len() % 2 == 0 {
+            let units: Vec<u16> = content.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+            String::from_utf16(&units).ok()
         } else {
-            String::from_utf16(bytemuck::cast_slice_mut(&mut content)).unwrap()
-        }
+            None
+        };
+        s.ok_or(Error::InvalidString { offset })
     }
-    fn write_string(file: &mut Cursor<Vec<u8>>, content: &str) {
-        let _bytes  = content.as_bytes();
+    fn write_string(file: &mut Cursor<Vec<u8>>, content: &str, utf8: bool) {
+        let _bytes: Vec<u8> = if utf8 {
+            content.as_bytes().to_vec()
+        } else {
+            content.encode_utf16().flat_map(u16::to_le_bytes).collect()
+        };
         file.write_u32::<LE>(_bytes.len() as _).unwrap();
         if !_bytes.is_empty() {
-            file.write(_bytes).unwrap();
+            file.write_all(&_bytes).unwrap();
         }
     }
-    pub fn read_with_preset(content: Vec<u8>) -> Self {
-        let mut pmx = Self::read(content);
+    pub fn read_with_preset(content: Vec<u8>) -> Result<Self> {
+        let mut pmx = Self::read(content)?;
         pmx.reverse_ik_joints();
         pmx.linear_four_weight();
         pmx.scale(0.08);
         pmx.right_hand();
-        pmx
+        Ok(pmx)
     }
 
     pub fn write(&self) -> Vec<u8> {
+        self.write_with(&WriteOptions::default())
+    }
+
+    pub fn write_with(&self, options: &WriteOptions) -> Vec<u8> {
+        let utf8 = options.encoding == TextEncoding::Utf8;
+        let (
+            vertex_index_size,
+            texture_index_size,
+            material_index_size,
+            bone_index_size,
+            morph_index_size,
+            rigidbody_index_size,
+        ) = if options.compact_indices {
+            (
+                vertex_index_size(self.verts.len()),
+                index_size(self.texs.len()),
+                // `write_mats` and `write_bones` add one if there are none.
+                index_size(self.mats.len().max(1)),
+                index_size(self.bones.len().max(1)),
+                index_size(self.morphs.len()),
+                index_size(self.rigidbodys.len()),
+            )
+        } else {
+            (4, 4, 4, 4, 4, 4)
+        };
+
+        // QDEF weights and flip and impulse morphs are PMX 2.1 additions.
+        let v2_1 = self.verts.iter().any(|v| matches!(v.weight, VertexWeight::Quat(..)))
+            || self.morphs.iter().any(|m| matches!(m.morph, Morph::Flip(_) | Morph::Rigidbody(_)));
+
         let content = Vec::new();
         let mut file = std::io::Cursor::new(content);
-        file.write(b"PMX ").unwrap();
-        file.write_f32::<LE>(2.0).unwrap(); // version
+        file.write_all(b"PMX ").unwrap();
+        file.write_f32::<LE>(if v2_1 { 2.1 } else { 2.0 }).unwrap(); // version
         file.write_u8(8).unwrap(); // unknown
 
-        file.write_u8(1).unwrap(); // use uft-8
-        file.write_u8(0).unwrap(); // appendix_uv
-        file.write_u8(4).unwrap(); // vertex_index_size
-        file.write_u8(4).unwrap(); // texture_index_size
-        file.write_u8(4).unwrap(); // material_index_size
-        file.write_u8(4).unwrap(); // bone_index_size
-        file.write_u8(4).unwrap(); // morph_index_size
-        file.write_u8(4).unwrap(); // rigidbody_index_size
-
-        Self::write_string(&mut file, &self.name);
-        Self::write_string(&mut file, &self.name_en);
-        Self::write_string(&mut file, &self.comment);
-        Self::write_string(&mut file, &self.comment_en);
+        file.write_u8(if utf8 { 1 } else { 0 }).unwrap(); // 0 = UTF-16LE, 1 = UTF-8
+        file.write_u8(self.appendix_uv_count.min(4)).unwrap();
+        file.write_u8(vertex_index_size).unwrap();
+        file.write_u8(texture_index_size).unwrap();
+        file.write_u8(material_index_size).unwrap();
+        file.write_u8(bone_index_size).unwrap();
+        file.write_u8(morph_index_size).unwrap();
+        file.write_u8(rigidbody_index_size).unwrap();
+
+        Self::write_string(&mut file, &self.name, utf8);
+        Self::write_string(&mut file, &self.name_en, utf8);
+        Self::write_string(&mut file, &self.comment, utf8);
+        Self::write_string(&mut file, &self.comment_en, utf8);
         
-        self.write_verts(&mut file);
-        self.write_faces(&mut file);
-        file.write_u32::<LE>(0).unwrap(); // tex
-        self.write_mats(&mut file);
-        self.write_bones(&mut file);
-        file.write_u32::<LE>(0).unwrap(); // morphs
-
-
-        self.write_display_frames(&mut file);
-        file.write_u32::<LE>(0).unwrap(); // rigidbodys
-        file.write_u32::<LE>(0).unwrap(); // joints
+        self.write_verts(&mut file, bone_index_size);
+        self.write_faces(&mut file, vertex_index_size);
+        self.write_texs(&mut file, utf8);
+        self.write_mats(&mut file, utf8, texture_index_size);
+        self.write_bones(&mut file, utf8, bone_index_size);
+        self.write_morphs(
+            &mut file,
+            utf8,
+            vertex_index_size,
+            material_index_size,
+            bone_index_size,
+            morph_index_size,
+            rigidbody_index_size
+        );
+        self.write_display_frames(&mut file, utf8, bone_index_size, morph_index_size);
+        self.write_rigidbodys(&mut file, utf8, bone_index_size);
+        self.write_joints(&mut file, utf8, rigidbody_index_size);
+        if v2_1 {
+            file.write_u32::<LE>(0).unwrap(); // soft bodies
+        }
 
         file.into_inner()
     }
@@ -424,73 +524,130 @@ impl Pmx {
         file.write_f32::<LE>(v.z).unwrap();
         file.write_f32::<LE>(v.w).unwrap();
     }
-    fn write_vec4i(file: &mut Cursor<Vec<u8>>, v: IVec4) {
-        file.write_i32::<LE>(v.x).unwrap();
-        file.write_i32::<LE>(v.y).unwrap();
-        file.write_i32::<LE>(v.z).unwrap();
-        file.write_i32::<LE>(v.w).unwrap();
+    fn write_vec4i(file: &mut Cursor<Vec<u8>>, index_size: u8, v: IVec4) {
+        for i in v.to_array() {
+            Self::write_int(file, index_size, i);
+        }
     }
 
-    fn write_verts(&self, file: &mut Cursor<Vec<u8>>) {
+    /// Counterpart of `read_int`.
+    fn write_int(file: &mut Cursor<Vec<u8>>, index_size: u8, v: i32) {
+        match index_size {
+            1 => file.write_i8(v as i8).unwrap(),
+            2 => file.write_i16::<LE>(v as i16).unwrap(),
+            _ => file.write_i32::<LE>(v).unwrap(),
+        }
+    }
+    /// Counterpart of `read_vertex_index`.
+    fn write_vertex_index(file: &mut Cursor<Vec<u8>>, index_size: u8, v: u32) {
+        match index_size {
+            1 => file.write_u8(v as u8).unwrap(),
+            2 => file.write_u16::<LE>(v as u16).unwrap(),
+            _ => file.write_u32::<LE>(v).unwrap(),
+        }
+    }
+
+    /// `Four` weights with one or two influences as the `One` or `Two`
+    /// weight that deforms the same, if their weights add up to one.
+    fn compact_weight(weight: VertexWeight) -> VertexWeight {
+        let VertexWeight::Four(bi, bw) = weight else {
+            return weight;
+        };
+        let used: Vec<usize> = (0..4).filter(|&j| bw[j] != 0.0).collect();
+        let total: f32 = used.iter().map(|&j| bw[j]).sum();
+        if (total - 1.0).abs() > 1e-5 {
+            return weight;
+        }
+        match used[..] {
+            [a] => VertexWeight::One(bi[a]),
+            [a, b] => VertexWeight::Two(bi[a], bi[b], bw[a]),
+            _ => weight,
+        }
+    }
+
+    fn write_verts(&self, file: &mut Cursor<Vec<u8>>, bone_index_size: u8) {
         file.write_u32::<LE>(self.verts.len() as _).unwrap();
         for v in &self.verts {
             Self::write_vec3f(file, v.pos);
             Self::write_vec3f(file, v.nrm);
             Self::write_vec2f(file, v.uv);
-            match v.weight {
+            for uv in &v.appendix_uv[..self.appendix_uv_count.min(4) as usize] {
+                Self::write_vec4f(file, *uv);
+            }
+            match Self::compact_weight(v.weight) {
                 VertexWeight::One(b0) => {
                     file.write_u8(0).unwrap();
-                    file.write_i32::<LE>(b0).unwrap();
+                    Self::write_int(file, bone_index_size, b0);
+                },
+                VertexWeight::Two(b0, b1, w) => {
+                    file.write_u8(1).unwrap();
+                    Self::write_int(file, bone_index_size, b0);
+                    Self::write_int(file, bone_index_size, b1);
+                    file.write_f32::<LE>(w).unwrap();
                 },
-                VertexWeight::Two(_, _, _) => todo!(),
                 VertexWeight::Four(bi, bw) => {
                     file.write_u8(2).unwrap();
-                    Self::write_vec4i(file, bi);
+                    Self::write_vec4i(file, bone_index_size, bi);
+                    Self::write_vec4f(file, bw);
+                },
+                VertexWeight::Sphere(b0, b1, w, c, r0, r1) => {
+                    file.write_u8(3).unwrap();
+                    Self::write_int(file, bone_index_size, b0);
+                    Self::write_int(file, bone_index_size, b1);
+                    file.write_f32::<LE>(w).unwrap();
+                    Self::write_vec3f(file, c);
+                    Self::write_vec3f(file, r0);
+                    Self::write_vec3f(file, r1);
+                },
+                VertexWeight::Quat(bi, bw) => {
+                    file.write_u8(4).unwrap();
+                    Self::write_vec4i(file, bone_index_size, bi);
                     Self::write_vec4f(file, bw);
                 },
-                VertexWeight::Sphere(_, _, _, _, _, _) => todo!(),
-                VertexWeight::Quat(_, _) => todo!(),
             }
             file.write_f32::<LE>(v.edge_scale).unwrap();
         }
     }
 
-    fn write_faces(&self, file: &mut Cursor<Vec<u8>>) {
+    fn write_faces(&self, file: &mut Cursor<Vec<u8>>, vertex_index_size: u8) {
         file.write_u32::<LE>(3 * self.faces.len() as u32).unwrap();
 
         for f in &self.faces {
-            file.write_u32::<LE>(f[0]).unwrap();
-            file.write_u32::<LE>(f[1]).unwrap();
-            file.write_u32::<LE>(f[2]).unwrap();
+            Self::write_vertex_index(file, vertex_index_size, f[0]);
+            Self::write_vertex_index(file, vertex_index_size, f[1]);
+            Self::write_vertex_index(file, vertex_index_size, f[2]);
         }
     }
 
-    pub fn read(content: Vec<u8>) -> Self {
+    pub fn read(content: Vec<u8>) -> Result<Self> {
         let file = &mut std::io::Cursor::new(content);
-        let mut magic = vec![0u8; 3];
-        file.read_exact(&mut magic).unwrap();
-        file.read_u8().unwrap();
-        assert_eq!(String::from_utf8(magic), Ok("PMX".to_string()));
-        let version = file.read_f32::<LE>().unwrap();
-        file.read_u8().unwrap();
-        let utf8 = file.read_u8().unwrap() == 1;
-        let appendix_uv = file.read_u8().unwrap();
-        assert_eq!(appendix_uv, 0);
-        let vertex_index_size = file.read_u8().unwrap();
-        let texture_index_size = file.read_u8().unwrap();
-        let material_index_size = file.read_u8().unwrap();
-        let bone_index_size = file.read_u8().unwrap();
-        let morph_index_size = file.read_u8().unwrap();
-        let rigidbody_index_size = file.read_u8().unwrap();
-        let name = Pmx::read_string(file, utf8);
-        let name_en = Pmx::read_string(file, utf8);
-        let comment = Pmx::read_string(file, utf8);
-        let comment_en = Pmx::read_string(file, utf8);
-        let verts = Pmx::read_verts(file, bone_index_size);
-        let faces = Pmx::read_faces(file, vertex_index_size);
-        let texs = Pmx::read_texs(file, utf8);
-        let mats = Pmx::read_mats(file, utf8, texture_index_size);
-        let (bones, iks) = Pmx::read_bones(file, utf8, bone_index_size);
+        let magic = file.read_bytes(3)?;
+        file.u8()?;
+        if magic != b"PMX" {
+            return Err(Error::BadMagic { offset: 0 });
+        }
+        let _version = file.f32()?;
+        file.u8()?;
+        let utf8 = file.u8()? == 1;
+        let appendix_uv_count = file.u8()?;
+        if appendix_uv_count > 4 {
+            return Err(Error::InvalidValue { offset: file.position() - 1, what: "appendix uv count" });
+        }
+        let vertex_index_size = Pmx::read_index_size(file)?;
+        let texture_index_size = Pmx::read_index_size(file)?;
+        let material_index_size = Pmx::read_index_size(file)?;
+        let bone_index_size = Pmx::read_index_size(file)?;
+        let morph_index_size = Pmx::read_index_size(file)?;
+        let rigidbody_index_size = Pmx::read_index_size(file)?;
+        let name = Pmx::read_string(file, utf8)?;
+        let name_en = Pmx::read_string(file, utf8)?;
+        let comment = Pmx::read_string(file, utf8)?;
+        let comment_en = Pmx::read_string(file, utf8)?;
+        let verts = Pmx::read_verts(file, bone_index_size, appendix_uv_count)?;
+        let faces = Pmx::read_faces(file, vertex_index_size)?;
+        let texs = Pmx::read_texs(file, utf8)?;
+        let mats = Pmx::read_mats(file, utf8, texture_index_size)?;
+        let (bones, iks) = Pmx::read_bones(file, utf8, bone_index_size)?;
         let morphs = Pmx::read_morphs(
             file,
             utf8,
@@ -499,16 +656,17 @@ impl Pmx {
             bone_index_size,
             morph_index_size,
             rigidbody_index_size
-        );
-        Pmx::read_display_frames(file, utf8, bone_index_size, morph_index_size);
-        let rigidbodys = Pmx::read_rigidbodys(file, utf8, bone_index_size);
-        let joints = Pmx::read_joints(file, utf8, rigidbody_index_size);
+        )?;
+        let display_frames = Pmx::read_display_frames(file, utf8, bone_index_size, morph_index_size)?;
+        let rigidbodys = Pmx::read_rigidbodys(file, utf8, bone_index_size)?;
+        let joints = Pmx::read_joints(file, utf8, rigidbody_index_size)?;
 
-        Self {
+        Ok(Self {
             name,
             name_en,
             comment,
             comment_en,
+            appendix_uv_count,
             verts,
             faces,
             texs,
@@ -516,43 +674,44 @@ impl Pmx {
             bones,
             iks,
             morphs,
+            display_frames,
             rigidbodys,
             joints,
-        }
+        })
 
     }
 
-    fn read_mats(file: &mut Cursor<Vec<u8>>, utf8: bool, texture_index_size: u8) -> Vec<Mat> {
-        let len = file.read_u32::<LE>().unwrap();
+    fn read_mats(file: &mut Cursor<Vec<u8>>, utf8: bool, texture_index_size: u8) -> Result<Vec<Mat>> {
+        let len = file.u32()?;
 
-        let mut vct = Vec::with_capacity(len as usize);
+        let mut vct = Vec::with_capacity(Pmx::capacity(file, len));
         for _ in 0..len {
-            let name = Pmx::read_string(file, utf8);
-            let name_en = Pmx::read_string(file, utf8);
-            let diffuse = read_vec4f(file);
-            let specular = read_vec3f(file);
-            let specular_strength = file.read_f32::<LE>().unwrap();
-            let ambient = read_vec3f(file);
-            let draw_flag = DrawFlags::from_bits(file.read_u8().unwrap()).unwrap();
-            let edge_color = read_vec4f(file);
-            let edge_scale = file.read_f32::<LE>().unwrap();
-            let tex_index = Pmx::read_int(file, texture_index_size);
-            let env_index = Pmx::read_int(file, texture_index_size);
-            let env_blend_mode = match file.read_u8().unwrap() {
+            let name = Pmx::read_string(file, utf8)?;
+            let name_en = Pmx::read_string(file, utf8)?;
+            let diffuse = read_vec4f(file)?;
+            let specular = read_vec3f(file)?;
+            let specular_strength = file.f32()?;
+            let ambient = read_vec3f(file)?;
+            let draw_flag = DrawFlags::from_bits_retain(file.u8()?);
+            let edge_color = read_vec4f(file)?;
+            let edge_scale = file.f32()?;
+            let tex_index = Pmx::read_int(file, texture_index_size)?;
+            let env_index = Pmx::read_int(file, texture_index_size)?;
+            let env_blend_mode = match file.u8()? {
                 0 => BlendMode::Disable,
                 1 => BlendMode::Mul,
                 2 => BlendMode::Add,
                 3 => BlendMode::Other,
-                _ => unreachable!(),
+                _ => return Err(Error::InvalidValue { offset: file.position() - 1, what: "environment blend mode" }),
             };
-            let toon_ref = file.read_u8().unwrap();
+            let toon_ref = file.u8()?;
             let toon = if toon_ref == 0 {
-                Toon::Tex(Pmx::read_int(file, texture_index_size))
+                Toon::Tex(Pmx::read_int(file, texture_index_size)?)
             } else {
-                Toon::Inner(file.read_u8().unwrap())
+                Toon::Inner(file.u8()?)
             };
-            let comment = Pmx::read_string(file, utf8);
-            let associated_face_count = Pmx::read_int(file, 4) as u32 / 3;
+            let comment = Pmx::read_string(file, utf8)?;
+            let associated_face_count = Pmx::read_int(file, 4)? as u32 / 3;
             vct.push(Mat {
                 name,
                 name_en,
@@ -571,10 +730,10 @@ impl Pmx {
                 associated_face_count,
             });
         }
-        vct
+        Ok(vct)
     }
 
-    fn write_mats(&self, file: &mut Cursor<Vec<u8>>) {
+    fn write_mats(&self, file: &mut Cursor<Vec<u8>>, utf8: bool, texture_index_size: u8) {
         if self.faces.is_empty() {
             file.write_u32::<LE>(0).unwrap();
             return;
@@ -592,8 +751,8 @@ impl Pmx {
         };
         file.write_u32::<LE>(mats.len() as _).unwrap();
         for m in mats {
-            Self::write_string(file, &m.name);
-            Self::write_string(file, &m.name_en);
+            Self::write_string(file, &m.name, utf8);
+            Self::write_string(file, &m.name_en, utf8);
             Self::write_vec4f(file, m.diffuse);
             Self::write_vec3f(file, m.specular);
             file.write_f32::<LE>(m.specular_strength).unwrap();
@@ -601,8 +760,8 @@ impl Pmx {
             file.write_u8(m.draw_flag.bits()).unwrap();
             Self::write_vec4f(file, m.edge_color);
             file.write_f32::<LE>(m.edge_scale).unwrap();
-            file.write_i32::<LE>(m.tex_index).unwrap();
-            file.write_i32::<LE>(m.env_index).unwrap();
+            Self::write_int(file, texture_index_size, m.tex_index);
+            Self::write_int(file, texture_index_size, m.env_index);
             let env_blend_mode = match m.env_blend_mode {
                 BlendMode::Disable => 0,
                 BlendMode::Mul => 1,
@@ -613,7 +772,7 @@ impl Pmx {
             match m.toon {
                 Toon::Tex(i) => {
                     file.write_u8(0).unwrap();
-                    file.write_i32::<LE>(i).unwrap();
+                    Self::write_int(file, texture_index_size, i);
                 },
                 Toon::Inner(i) => {
                     file.write_u8(1).unwrap();
@@ -621,11 +780,37 @@ impl Pmx {
                 },
             }
 
-            Self::write_string(file, &m.comment);
+            Self::write_string(file, &m.comment, utf8);
             file.write_u32::<LE>(m.associated_face_count * 3).unwrap();
         }
     }
-    fn write_bones(&self, file: &mut Cursor<Vec<u8>>) {
+    /// Flags of bone `i` as written: the ones that announce a tail bone,
+    /// inherit, axes, external parent or IK follow the fields that hold
+    /// their data, and the rest come from `bone_flags`. An `inherit` whose
+    /// flags name neither rotation nor translation inherits rotation.
+    fn bone_flags_for(&self, i: usize, b: &Bone) -> BoneFlags {
+        let derived = BoneFlags::INDEXED_TAIL_BONE
+            | BoneFlags::INHERIT_ROTATION
+            | BoneFlags::INHERIT_TRANSLATION
+            | BoneFlags::FIXED_AXIS
+            | BoneFlags::LOCAL_AXIS
+            | BoneFlags::EXTERNAL_PARENT
+            | BoneFlags::IK;
+        let mut flags = b.bone_flags.difference(derived);
+        flags.set(BoneFlags::INDEXED_TAIL_BONE, matches!(b.bone_tail_pos, BoneTailPos::Bone(_)));
+        if b.inherit.is_some() {
+            let inherit = b.bone_flags & (BoneFlags::INHERIT_ROTATION | BoneFlags::INHERIT_TRANSLATION);
+            flags |= if inherit.is_empty() { BoneFlags::INHERIT_ROTATION } else { inherit };
+        }
+        flags.set(BoneFlags::FIXED_AXIS, b.fixed_axis.is_some());
+        flags.set(BoneFlags::LOCAL_AXIS, b.local_axis.is_some());
+        flags.set(BoneFlags::EXTERNAL_PARENT, b.external_parent.is_some());
+        flags.set(BoneFlags::IK, self.iks.iter().any(|ik| ik.bone == i as i32));
+        flags
+    }
+    /// Writes each bone's fields in the order `read_bones` reads them. The
+    /// IK block of a bone is the first entry of `iks` naming it.
+    fn write_bones(&self, file: &mut Cursor<Vec<u8>>, utf8: bool, bone_index_size: u8) {
         let default = vec![ Bone::default() ];
 
         let bones = if self.bones.is_empty() {
@@ -634,88 +819,111 @@ impl Pmx {
             &self.bones
         };
         file.write_u32::<LE>(bones.len() as _).unwrap();
-        for b in bones {
-            Self::write_string(file, &b.name);
-            Self::write_string(file, &b.name_en);
+        for (i, b) in bones.iter().enumerate() {
+            Self::write_string(file, &b.name, utf8);
+            Self::write_string(file, &b.name_en, utf8);
             Self::write_vec3f(file, b.pos);
-            if let Some(p) = b.parent_index {
-                file.write_i32::<LE>(p as _).unwrap();
-            } else {
-                file.write_i32::<LE>(-1).unwrap();
-            };
+            Self::write_int(file, bone_index_size, b.parent_index.map_or(-1, |p| p as i32));
             file.write_i32::<LE>(b.layer).unwrap();
 
-            let mut bitflags = b.bone_flags.bits() & BoneFlags::INDEXED_TAIL_BONE.bits();
-            bitflags |= BoneFlags::ROTATABLE.bits();
-            bitflags |= BoneFlags::TRANSLATABLE.bits();
-            bitflags |= BoneFlags::VISIBLE.bits();
-            bitflags |= BoneFlags::ENABLED.bits();
-
-            file.write_u16::<LE>(bitflags).unwrap();
+            let bone_flags = self.bone_flags_for(i, b);
+            file.write_u16::<LE>(bone_flags.bits()).unwrap();
             match b.bone_tail_pos {
                 BoneTailPos::Bone(bi) => {
-                    file.write_i32::<LE>(bi).unwrap();
+                    Self::write_int(file, bone_index_size, bi);
                 },
                 BoneTailPos::Pos(pos) => {
                     Self::write_vec3f(file, pos);
                 },
             }
+            if let Some((parent_index, affect)) = b.inherit {
+                Self::write_int(file, bone_index_size, parent_index);
+                file.write_f32::<LE>(affect).unwrap();
+            }
+            if let Some(axis) = b.fixed_axis {
+                Self::write_vec3f(file, axis);
+            }
+            if let Some((x, z)) = b.local_axis {
+                Self::write_vec3f(file, x);
+                Self::write_vec3f(file, z);
+            }
+            if let Some(key) = b.external_parent {
+                file.write_i32::<LE>(key).unwrap();
+            }
+            if let Some(ik) = self.iks.iter().find(|ik| ik.bone == i as i32) {
+                Self::write_int(file, bone_index_size, ik.effector);
+                file.write_i32::<LE>(ik.loop_count).unwrap();
+                file.write_f32::<LE>(ik.limit_angle).unwrap();
+                file.write_i32::<LE>(ik.ik_joints.len() as _).unwrap();
+                for joint in &ik.ik_joints {
+                    Self::write_int(file, bone_index_size, joint.bone);
+                    match joint.limit {
+                        Some((limit_min, limit_max)) => {
+                            file.write_u8(1).unwrap();
+                            Self::write_vec3f(file, limit_min);
+                            Self::write_vec3f(file, limit_max);
+                        },
+                        None => file.write_u8(0).unwrap(),
+                    }
+                }
+            }
         }
     }
-    fn read_bones(file: &mut Cursor<Vec<u8>>, utf8: bool, bone_index_size: u8) -> (Vec<Bone>, Vec<Ik>) {
-        let len = file.read_u32::<LE>().unwrap();
-        let mut vct = Vec::with_capacity(len as usize);
-        let mut iks = Vec::with_capacity(len as usize);
+    fn read_bones(file: &mut Cursor<Vec<u8>>, utf8: bool, bone_index_size: u8) -> Result<(Vec<Bone>, Vec<Ik>)> {
+        let len = file.u32()?;
+        let mut vct = Vec::with_capacity(Pmx::capacity(file, len));
+        let mut iks = Vec::with_capacity(Pmx::capacity(file, len));
         for i in 0..len {
-            let name = Pmx::read_string(file, utf8);
-            let name_en = Pmx::read_string(file, utf8);
-            let pos = read_vec3f(file);
-            let parent_index = Pmx::read_int(file, bone_index_size);
+            let name = Pmx::read_string(file, utf8)?;
+            let name_en = Pmx::read_string(file, utf8)?;
+            let pos = read_vec3f(file)?;
+            let parent_index = Pmx::read_int(file, bone_index_size)?;
             let parent_index = if parent_index >= 0 {
                 Some(parent_index as usize)
             } else {
                 None
             };
-            let layer = file.read_i32::<LE>().unwrap();
-            let bone_flags = BoneFlags::from_bits(file.read_u16::<LE>().unwrap()).unwrap();
+            let layer = file.i32()?;
+            let bone_flags = BoneFlags::from_bits_retain(file.u16()?);
             let bone_tail_pos = if bone_flags.contains(BoneFlags::INDEXED_TAIL_BONE) {
-                BoneTailPos::Bone(Pmx::read_int(file, bone_index_size))
+                BoneTailPos::Bone(Pmx::read_int(file, bone_index_size)?)
             } else {
-                BoneTailPos::Pos(read_vec3f(file))
+                BoneTailPos::Pos(read_vec3f(file)?)
             };
             let inherit = if bone_flags.contains(BoneFlags::INHERIT_ROTATION) || bone_flags.contains(BoneFlags::INHERIT_TRANSLATION) {
-                let parent_index = Pmx::read_int(file, bone_index_size);
-                let affect = file.read_f32::<LE>().unwrap();
+                let parent_index = Pmx::read_int(file, bone_index_size)?;
+                let affect = file.f32()?;
                 Some((parent_index, affect))
             } else {
                 None
             };
             let fixed_axis = if bone_flags.contains(BoneFlags::FIXED_AXIS) {
-                Some(read_vec3f(file))
+                Some(read_vec3f(file)?)
             } else {
                 None
             };
             let local_axis = if bone_flags.contains(BoneFlags::LOCAL_AXIS) {
-                Some((read_vec3f(file), read_vec3f(file)))
+                Some((read_vec3f(file)?, read_vec3f(file)?))
             } else {
                 None
             };
+            // The external parent is a 4-byte key, not a bone index.
             let external_parent = if bone_flags.contains(BoneFlags::EXTERNAL_PARENT) {
-                Some(Pmx::read_int(file, bone_index_size))
+                Some(file.i32()?)
             } else {
                 None
             };
             if bone_flags.contains(BoneFlags::IK) {
-                let effector = Pmx::read_int(file, bone_index_size);
-                let loop_count = file.read_i32::<LE>().unwrap();
-                let limit_angle = file.read_f32::<LE>().unwrap();
-                let link_count = file.read_i32::<LE>().unwrap();
+                let effector = Pmx::read_int(file, bone_index_size)?;
+                let loop_count = file.i32()?;
+                let limit_angle = file.f32()?;
+                let link_count = file.i32()?;
                 let mut ik_joints = Vec::new();
-                for i in 0..link_count {
-                    let bone = Pmx::read_int(file, bone_index_size);
-                    let limit = if file.read_u8().unwrap() == 1 {
-                        let limit_min = read_vec3f(file);
-                        let limit_max = read_vec3f(file);
+                for _ in 0..link_count {
+                    let bone = Pmx::read_int(file, bone_index_size)?;
+                    let limit = if file.u8()? == 1 {
+                        let limit_min = read_vec3f(file)?;
+                        let limit_max = read_vec3f(file)?;
                         Some((limit_min, limit_max))
                     } else {
                         None
@@ -747,36 +955,62 @@ impl Pmx {
                 external_parent,
             })
         }
-        (vct, iks)
+        Ok((vct, iks))
     }
 
-    fn read_texs(file: &mut Cursor<Vec<u8>>, utf8: bool) -> Vec<String> {
-        let len = file.read_u32::<LE>().unwrap();
-        let mut vct = Vec::with_capacity(len as usize);
+    fn read_texs(file: &mut Cursor<Vec<u8>>, utf8: bool) -> Result<Vec<String>> {
+        let len = file.u32()?;
+        let mut vct = Vec::with_capacity(Pmx::capacity(file, len));
         for _ in 0..len {
-            let tex = Pmx::read_string(file, utf8);
+            let tex = Pmx::read_string(file, utf8)?;
             vct.push(tex)
         }
-        vct
+        Ok(vct)
     }
-    fn read_joints(file: &mut Cursor<Vec<u8>>, utf8: bool, rigidbody_index_size: u8) -> Vec<Joint> {
-        let len = file.read_u32::<LE>().unwrap();
-        let mut vct = Vec::with_capacity(len as usize);
+    fn write_texs(&self, file: &mut Cursor<Vec<u8>>, utf8: bool) {
+        file.write_u32::<LE>(self.texs.len() as _).unwrap();
+        for tex in &self.texs {
+            Self::write_string(file, tex, utf8);
+        }
+    }
+    fn write_joints(&self, file: &mut Cursor<Vec<u8>>, utf8: bool, rigidbody_index_size: u8) {
+        file.write_u32::<LE>(self.joints.len() as _).unwrap();
+        for j in &self.joints {
+            Self::write_string(file, &j.name, utf8);
+            Self::write_string(file, &j.name_en, utf8);
+            file.write_u8(j.category).unwrap();
+            Self::write_int(file, rigidbody_index_size, j.rigidbody_a);
+            Self::write_int(file, rigidbody_index_size, j.rigidbody_b);
+            Self::write_vec3f(file, j.pos);
+            Self::write_vec3f(file, j.rot);
+            Self::write_vec3f(file, j.pos_min);
+            Self::write_vec3f(file, j.pos_max);
+            Self::write_vec3f(file, j.rot_min);
+            Self::write_vec3f(file, j.rot_max);
+            Self::write_vec3f(file, j.pos_spring);
+            Self::write_vec3f(file, j.rot_spring);
+        }
+    }
+    fn read_joints(file: &mut Cursor<Vec<u8>>, utf8: bool, rigidbody_index_size: u8) -> Result<Vec<Joint>> {
+        let len = file.u32()?;
+        let mut vct = Vec::with_capacity(Pmx::capacity(file, len));
         for _ in 0..len {
-            let name = Pmx::read_string(file, utf8);
-            let name_en = Pmx::read_string(file, utf8);
-            let category = file.read_u8().unwrap();
-            assert_eq!(category, 0);
-            let rigidbody_a = Pmx::read_int(file, rigidbody_index_size);
-            let rigidbody_b = Pmx::read_int(file, rigidbody_index_size);
-            let pos = read_vec3f(file);
-            let rot = read_vec3f(file);
-            let pos_min = read_vec3f(file);
-            let pos_max = read_vec3f(file);
-            let rot_min = read_vec3f(file);
-            let rot_max = read_vec3f(file);
-            let pos_spring = read_vec3f(file);
-            let rot_spring = read_vec3f(file);
+            let name = Pmx::read_string(file, utf8)?;
+            let name_en = Pmx::read_string(file, utf8)?;
+            let category = file.u8()?;
+            if category != 0 {
+                return Err(Error::InvalidValue { offset: file.position() - 1, what: "joint type" });
+            }
+            let rigidbody_a = Pmx::read_int(file, rigidbody_index_size)?;
+            let rigidbody_b = Pmx::read_int(file, rigidbody_index_size)?;
+            let pos = read_vec3f(file)?;
+            let rot = read_vec3f(file)?;
+            let pos_min = read_vec3f(file)?;
+            let pos_max = read_vec3f(file)?;
+            let rot_min = read_vec3f(file)?;
+            let rot_max = read_vec3f(file)?;
+            let pos_spring = read_vec3f(file)?;
+            let rot_spring = read_vec3f(file)?;
             vct.push(Joint {
                 name,
                 name_en,
@@ -793,37 +1027,37 @@ impl Pmx {
                 rot_spring,
             });
         }
-        vct
+        Ok(vct)
     }
 
-    fn read_rigidbodys(file: &mut Cursor<Vec<u8>>, utf8: bool, bone_index_size: u8) -> Vec<Rigidbody> {
-        let len = file.read_u32::<LE>().unwrap();
-        let mut vct = Vec::with_capacity(len as usize);
+    fn read_rigidbodys(file: &mut Cursor<Vec<u8>>, utf8: bool, bone_index_size: u8) -> Result<Vec<Rigidbody>> {
+        let len = file.u32()?;
+        let mut vct = Vec::with_capacity(Pmx::capacity(file, len));
         for _ in 0..len {
-            let name = Pmx::read_string(file, utf8);
-            let name_en = Pmx::read_string(file, utf8);
-            let bone = Pmx::read_int(file, bone_index_size);
-            let group = file.read_u8().unwrap();
-            let collision_group = file.read_u16::<LE>().unwrap();
-            let shape = match file.read_u8().unwrap() {
+            let name = Pmx::read_string(file, utf8)?;
+            let name_en = Pmx::read_string(file, utf8)?;
+            let bone = Pmx::read_int(file, bone_index_size)?;
+            let group = file.u8()?;
+            let collision_group = file.u16()?;
+            let shape = match file.u8()? {
                 0 => RigidbodyShape::Shpere,
                 1 => RigidbodyShape::Box,
                 2 => RigidbodyShape::Capsule,
-                _ => unreachable!(),
+                _ => return Err(Error::InvalidValue { offset: file.position() - 1, what: "rigidbody shape" }),
             };
-            let size = read_vec3f(file);
-            let pos = read_vec3f(file);
-            let rot = read_vec3f(file);
-            let mass = file.read_f32::<LE>().unwrap();
-            let linear_damping = file.read_f32::<LE>().unwrap();
-            let angular_damping = file.read_f32::<LE>().unwrap();
-            let restitution = file.read_f32::<LE>().unwrap();
-            let friction = file.read_f32::<LE>().unwrap();
-            let mode = match file.read_u8().unwrap() {
+            let size = read_vec3f(file)?;
+            let pos = read_vec3f(file)?;
+            let rot = read_vec3f(file)?;
+            let mass = file.f32()?;
+            let linear_damping = file.f32()?;
+            let angular_damping = file.f32()?;
+            let restitution = file.f32()?;
+            let friction = file.f32()?;
+            let mode = match file.u8()? {
                 0 => RigidbodyMode::Kinematics,
                 1 => RigidbodyMode::Dynamics,
                 2 => RigidbodyMode::DynamicsPassRotation,
-                _ => unreachable!(),
+                _ => return Err(Error::InvalidValue { offset: file.position() - 1, what: "rigidbody mode" }),
             };
             vct.push(Rigidbody {
                 name,
@@ -843,51 +1077,89 @@ impl Pmx {
                 mode,
             });
         }
-        vct
+        Ok(vct)
     }
 
-    fn write_display_frames(&self, file: &mut Cursor<Vec<u8>>) {
-        let display_frames: Vec<DisplayFrame> = vec![
-            DisplayFrame { name: "Root".to_string(), name_en: "Root".to_string(), deletable: true, morph_items: vec![DisplayFrameIndex::Bone(0)] },
-            DisplayFrame { name: "表情".to_string(), name_en: "Exp".to_string(), deletable: true, morph_items: vec![] },
-        ];
+    fn write_rigidbodys(&self, file: &mut Cursor<Vec<u8>>, utf8: bool, bone_index_size: u8) {
+        file.write_u32::<LE>(self.rigidbodys.len() as _).unwrap();
+        for r in &self.rigidbodys {
+            Self::write_string(file, &r.name, utf8);
+            Self::write_string(file, &r.name_en, utf8);
+            Self::write_int(file, bone_index_size, r.bone);
+            file.write_u8(r.group).unwrap();
+            file.write_u16::<LE>(r.collision_group).unwrap();
+            let shape = match r.shape {
+                RigidbodyShape::Shpere => 0,
+                RigidbodyShape::Box => 1,
+                RigidbodyShape::Capsule => 2,
+            };
+            file.write_u8(shape).unwrap();
+            Self::write_vec3f(file, r.size);
+            Self::write_vec3f(file, r.pos);
+            Self::write_vec3f(file, r.rot);
+            file.write_f32::<LE>(r.mass).unwrap();
+            file.write_f32::<LE>(r.linear_damping).unwrap();
+            file.write_f32::<LE>(r.angular_damping).unwrap();
+            file.write_f32::<LE>(r.restitution).unwrap();
+            file.write_f32::<LE>(r.friction).unwrap();
+            let mode = match r.mode {
+                RigidbodyMode::Kinematics => 0,
+                RigidbodyMode::Dynamics => 1,
+                RigidbodyMode::DynamicsPassRotation => 2,
+            };
+            file.write_u8(mode).unwrap();
+        }
+    }
 
-        file.write_u32::<LE>(display_frames.len() as _).unwrap();
-        for df in &display_frames {
-            Self::write_string(file, &df.name);
-            Self::write_string(file, &df.name_en);
+    /// The two special frames MMD expects: "Root" holding the first bone
+    /// and "表情" holding every morph.
+    pub fn default_display_frames(&self) -> Vec<DisplayFrame> {
+        vec![
+            DisplayFrame { name: "Root".to_string(), name_en: "Root".to_string(), deletable: true, morph_items: vec![DisplayFrameIndex::Bone(0)] },
+            DisplayFrame {
+                name: "表情".to_string(),
+                name_en: "Exp".to_string(),
+                deletable: true,
+                morph_items: (0..self.morphs.len() as u32).map(DisplayFrameIndex::Morph).collect(),
+            },
+        ]
+    }
+    fn write_display_frames(&self, file: &mut Cursor<Vec<u8>>, utf8: bool, bone_index_size: u8, morph_index_size: u8) {
+        file.write_u32::<LE>(self.display_frames.len() as _).unwrap();
+        for df in &self.display_frames {
+            Self::write_string(file, &df.name, utf8);
+            Self::write_string(file, &df.name_en, utf8);
             file.write_u8(if df.deletable { 1 } else { 0 }).unwrap();
             file.write_i32::<LE>(df.morph_items.len() as _).unwrap();
             for index in &df.morph_items {
                 match index {
                     DisplayFrameIndex::Bone(bi) => {
                         file.write_u8(0).unwrap();
-                        file.write_u32::<LE>(*bi).unwrap();
-                        
+                        Self::write_int(file, bone_index_size, *bi as _);
                     },
                     DisplayFrameIndex::Morph(mi) => {
                         file.write_u8(1).unwrap();
-                        file.write_u32::<LE>(*mi).unwrap();
+                        Self::write_int(file, morph_index_size, *mi as _);
                     },
                 }
             }
         }
     }
-    fn read_display_frames(file: &mut Cursor<Vec<u8>>, utf8: bool, bone_index_size: u8, morph_index_size: u8) -> Vec<DisplayFrame> {
-        let len = file.read_u32::<LE>().unwrap();
-        let mut vct = Vec::with_capacity(len as usize);
+    fn read_display_frames(file: &mut Cursor<Vec<u8>>, utf8: bool, bone_index_size: u8, morph_index_size: u8) -> Result<Vec<DisplayFrame>> {
+        let len = file.u32()?;
+        let mut vct = Vec::with_capacity(Pmx::capacity(file, len));
         for _ in 0..len {
-            let name = Pmx::read_string(file, utf8);
-            let name_en = Pmx::read_string(file, utf8);
-            let deletable = file.read_i8().unwrap() == 1;
-            let frame_count = file.read_i32::<LE>().unwrap();
+            let name = Pmx::read_string(file, utf8)?;
+            let name_en = Pmx::read_string(file, utf8)?;
+            let deletable = file.i8()? == 1;
+            let frame_count = file.i32()?;
             let mut morph_items = Vec::new();
             for __ in 0..frame_count {
-                let is_morph_frame = file.read_u8().unwrap() == 1;
+                let is_morph_frame = file.u8()? == 1;
                 morph_items.push(if is_morph_frame {
-                    DisplayFrameIndex::Morph(Pmx::read_int(file, morph_index_size) as u32)
+                    DisplayFrameIndex::Morph(Pmx::read_int(file, morph_index_size)? as u32)
                 } else {
-                    DisplayFrameIndex::Bone(Pmx::read_int(file, bone_index_size) as u32)
+                    DisplayFrameIndex::Bone(Pmx::read_int(file, bone_index_size)? as u32)
                 });
             }
             vct.push(DisplayFrame {
@@ -897,7 +1169,98 @@ impl Pmx {
                 morph_items,
             });
         }
-        vct
+        Ok(vct)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn write_morphs(
+        &self,
+        file: &mut Cursor<Vec<u8>>,
+        utf8: bool,
+        vertex_index_size: u8,
+        material_index_size: u8,
+        bone_index_size: u8,
+        morph_index_size: u8,
+        rigidbody_index_size: u8
+    ) {
+        file.write_u32::<LE>(self.morphs.len() as _).unwrap();
+        for m in &self.morphs {
+            Self::write_string(file, &m.name, utf8);
+            Self::write_string(file, &m.name_en, utf8);
+            file.write_i8(m.panel).unwrap();
+            let (category, count) = match &m.morph {
+                Morph::Group(v) => (0, v.len()),
+                Morph::Vertex(v) => (1, v.len()),
+                Morph::Bone(v) => (2, v.len()),
+                Morph::Uv(v) => (if (3..=7).contains(&m.category) { m.category } else { 3 }, v.len()),
+                Morph::Mat(v) => (8, v.len()),
+                Morph::Flip(v) => (9, v.len()),
+                Morph::Rigidbody(v) => (10, v.len()),
+            };
+            file.write_i8(category).unwrap();
+            file.write_i32::<LE>(count as _).unwrap();
+            match &m.morph {
+                Morph::Group(v) => {
+                    for item in v {
+                        Self::write_int(file, morph_index_size, item.index as _);
+                        file.write_f32::<LE>(item.affect).unwrap();
+                    }
+                },
+                Morph::Vertex(v) => {
+                    for item in v {
+                        Self::write_vertex_index(file, vertex_index_size, item.index);
+                        Self::write_vec3f(file, item.trans);
+                    }
+                },
+                Morph::Bone(v) => {
+                    for item in v {
+                        Self::write_int(file, bone_index_size, item.index as _);
+                        Self::write_vec3f(file, item.trans);
+                        Self::write_vec4f(file, item.rot);
+                    }
+                },
+                Morph::Uv(v) => {
+                    for item in v {
+                        Self::write_vertex_index(file, vertex_index_size, item.index);
+                        Self::write_vec4f(file, item.trans);
+                    }
+                },
+                Morph::Mat(v) => {
+                    for item in v {
+                        Self::write_int(file, material_index_size, item.index as _);
+                        // Material morphs only multiply or add.
+                        let blend_mode = match item.blend_mode {
+                            BlendMode::Add => 1,
+                            _ => 0,
+                        };
+                        file.write_u8(blend_mode).unwrap();
+                        Self::write_vec4f(file, item.diffuse);
+                        Self::write_vec3f(file, item.specular);
+                        file.write_f32::<LE>(item.specularity).unwrap();
+                        Self::write_vec3f(file, item.ambient);
+                        Self::write_vec4f(file, item.edge_color);
+                        file.write_f32::<LE>(item.edge_size).unwrap();
+                        Self::write_vec4f(file, item.texture_tint);
+                        Self::write_vec4f(file, item.environment_tint);
+                        Self::write_vec4f(file, item.toon_tint);
+                    }
+                },
+                Morph::Flip(v) => {
+                    for item in v {
+                        Self::write_int(file, morph_index_size, item.index as _);
+                        file.write_f32::<LE>(item.affect).unwrap();
+                    }
+                },
+                Morph::Rigidbody(v) => {
+                    for item in v {
+                        Self::write_int(file, rigidbody_index_size, item.index as _);
+                        file.write_u8(if item.local { 1 } else { 0 }).unwrap();
+                        Self::write_vec3f(file, item.trans_speed);
+                        Self::write_vec3f(file, item.rot_torque);
+                    }
+                },
+            }
+        }
     }
 
     fn read_morphs(
@@ -908,87 +1271,80 @@ impl Pmx {
         bone_index_size: u8,
         morph_index_size: u8,
         rigidbody_index_size: u8
-    ) -> Vec<MorphInfo> {
-        let len = file.read_u32::<LE>().unwrap();
-        let mut vct = Vec::with_capacity(len as usize);
+    ) -> Result<Vec<MorphInfo>> {
+        let len = file.u32()?;
+        let mut vct = Vec::with_capacity(Pmx::capacity(file, len));
         for _ in 0..len {
-            let name = Pmx::read_string(file, utf8);
-            let name_en = Pmx::read_string(file, utf8);
-            let panel = file.read_i8().unwrap();
-            let category = file.read_i8().unwrap();
-            let count = file.read_i32::<LE>().unwrap();
-            vct.push(MorphInfo{
-                name,
-                name_en,
-                panel,
-                category,
-            });
-            if category == 0 {
+            let name = Pmx::read_string(file, utf8)?;
+            let name_en = Pmx::read_string(file, utf8)?;
+            let panel = file.i8()?;
+            let category_offset = file.position();
+            let category = file.i8()?;
+            let count = file.i32()?;
+            let morph = if category == 0 {
                 let mut v = Vec::new();
                 for __ in 0..count {
-                    let index = Pmx::read_int(file, morph_index_size) as u32;
-                    let affect = file.read_f32::<LE>().unwrap();
+                    let index = Pmx::read_int(file, morph_index_size)? as u32;
+                    let affect = file.f32()?;
                     v.push(MorphGroupItem {
                         index,
                         affect,
                     });
                 }
-                // vct.push(Morph::MorphGroup(v));
+                Morph::Group(v)
             } else if category == 1 {
                 let mut v = Vec::new();
                 for __ in 0..count {
-                    let index = Pmx::read_int(file, vertex_index_size) as u32;
-                    let trans = read_vec3f(file);
+                    let index = Pmx::read_vertex_index(file, vertex_index_size)?;
+                    let trans = read_vec3f(file)?;
                     v.push(MorphVertexItem {
                         index,
                         trans,
                     });
                 }
-                // vct.push(Morph::MorphVertex(v));
+                Morph::Vertex(v)
             } else if category == 2 {
                 let mut v = Vec::new();
                 for __ in 0..count {
-                    let index = Pmx::read_int(file, bone_index_size) as u32;
-                    let trans = read_vec3f(file);
-                    let rot = read_vec4f(file);
+                    let index = Pmx::read_int(file, bone_index_size)? as u32;
+                    let trans = read_vec3f(file)?;
+                    let rot = read_vec4f(file)?;
                     v.push(MorphBoneItem {
                         index,
                         trans,
                         rot,
                     })
                 }
-                // vct.push(Morph::MorphBone(v));
-            } else if category == 3 {
+                Morph::Bone(v)
+            } else if (3..=7).contains(&category) {
                 let mut v = Vec::new();
                 for __ in 0..count {
-                    let index = Pmx::read_int(file, vertex_index_size) as u32;
-                    let trans = read_vec4f(file);
+                    let index = Pmx::read_vertex_index(file, vertex_index_size)?;
+                    let trans = read_vec4f(file)?;
                     v.push(MorphUvItem {
                         index,
                         trans,
                     })
                 }
-                // vct.push(Morph::MorphUv(v));
-            } else if category == 4 || category == 5 || category == 6 || category == 7 {
-                unreachable!()
+                Morph::Uv(v)
             } else if category == 8 {
                 let mut v = Vec::new();
                 for __ in 0..count {
-                    let index = Pmx::read_int(file, material_index_size) as u32;
-                    let blend_mode = match file.read_u8().unwrap() {
+                    let index = Pmx::read_int(file, material_index_size)? as u32;
+                    let blend_mode = match file.u8()? {
                         0 => BlendMode::Mul, 
                         1 => BlendMode::Add, 
-                        _ => unreachable!(),
+                        _ => return Err(Error::InvalidValue { offset: file.position() - 1, what: "material morph blend mode" }),
                     };
-                    let diffuse = read_vec4f(file);
-                    let specular = read_vec3f(file);
-                    let specularity = file.read_f32::<LE>().unwrap();
-                    let ambient = read_vec3f(file);
-                    let edge_color = read_vec4f(file);
-                    let edge_size = file.read_f32::<LE>().unwrap();
-                    let texture_tint = read_vec4f(file);
-                    let environment_tint = read_vec4f(file);
-                    let toon_tint = read_vec4f(file);
+                    let diffuse = read_vec4f(file)?;
+                    let specular = read_vec3f(file)?;
+                    let specularity = file.f32()?;
+                    let ambient = read_vec3f(file)?;
+                    let edge_color = read_vec4f(file)?;
+                    let edge_size = file.f32()?;
+                    let texture_tint = read_vec4f(file)?;
+                    let environment_tint = read_vec4f(file)?;
+                    let toon_tint = read_vec4f(file)?;
                     v.push(MorphMatItem {
                         index,
                         blend_mode,
@@ -1003,25 +1359,25 @@ impl Pmx {
                         toon_tint,
                     });
                 }
-                // vct.push(Morph::MorphMat(v));
+                Morph::Mat(v)
             } else if category == 9 {
                 let mut v = Vec::new();
                 for __ in 0..count {
-                    let index = Pmx::read_int(file, morph_index_size) as u32;
-                    let affect = file.read_f32::<LE>().unwrap();
+                    let index = Pmx::read_int(file, morph_index_size)? as u32;
+                    let affect = file.f32()?;
                     v.push(MorphFlipItem {
                         index,
                         affect,
                     })
                 }
-                // vct.push(Morph::MorphFlip(v));
+                Morph::Flip(v)
             } else if category == 10 {
                 let mut v = Vec::new();
                 for __ in 0..count {
-                    let index = Pmx::read_int(file, rigidbody_index_size) as u32;
-                    let local = file.read_u8().unwrap() == 1;
-                    let trans_speed = read_vec3f(file);
-                    let rot_torque = read_vec3f(file);
+                    let index = Pmx::read_int(file, rigidbody_index_size)? as u32;
+                    let local = file.u8()? == 1;
+                    let trans_speed = read_vec3f(file)?;
+                    let rot_torque = read_vec3f(file)?;
                     v.push(MorphRigidbodyItem {
                         index,
                         local,
@@ -1029,88 +1385,123 @@ impl Pmx {
                         rot_torque,
                     });
                 }
-                // vct.push(Morph::MorphRigidbody(v));
-            }
+                Morph::Rigidbody(v)
+            } else {
+                return Err(Error::InvalidValue { offset: category_offset, what: "morph type" });
+            };
+            vct.push(MorphInfo {
+                name,
+                name_en,
+                panel,
+                category,
+                morph,
+            });
         }
-        vct
+        Ok(vct)
     }
 
-    fn read_faces(file: &mut Cursor<Vec<u8>>, vertex_index_size: u8) -> Vec<[u32; 3]> {
-        let len = file.read_u32::<LE>().unwrap() / 3;
+    fn read_faces(file: &mut Cursor<Vec<u8>>, vertex_index_size: u8) -> Result<Vec<[u32; 3]>> {
+        let len = file.u32()? / 3;
 
-        let mut vct = Vec::with_capacity(len as usize);
+        let mut vct = Vec::with_capacity(Pmx::capacity(file, len));
         for _ in 0..len {
-            let a = Pmx::read_int(file, vertex_index_size) as u32;
-            let b = Pmx::read_int(file, vertex_index_size) as u32;
-            let c = Pmx::read_int(file, vertex_index_size) as u32;
+            let a = Pmx::read_vertex_index(file, vertex_index_size)?;
+            let b = Pmx::read_vertex_index(file, vertex_index_size)?;
+            let c = Pmx::read_vertex_index(file, vertex_index_size)?;
             vct.push([a, b, c])
         }
-        vct
+        Ok(vct)
     }
-    fn read_verts(file: &mut Cursor<Vec<u8>>, bone_index_size: u8) -> Vec<Vertex> {
-        let len = file.read_u32::<LE>().unwrap();
-        let mut vct = Vec::with_capacity(len as usize);
-        for i in 0..len {
-            let pos = read_vec3f(file);
-            let nrm = read_vec3f(file);
-            let uv = read_vec2f(file);
-            let weight_type = file.read_u8().unwrap();
+    fn read_verts(file: &mut Cursor<Vec<u8>>, bone_index_size: u8, appendix_uv_count: u8) -> Result<Vec<Vertex>> {
+        let len = file.u32()?;
+        let mut vct = Vec::with_capacity(Pmx::capacity(file, len));
+        for _ in 0..len {
+            let pos = read_vec3f(file)?;
+            let nrm = read_vec3f(file)?;
+            let uv = read_vec2f(file)?;
+            let mut appendix_uv = [Vec4::ZERO; 4];
+            for uv in &mut appendix_uv[..appendix_uv_count as usize] {
+                *uv = read_vec4f(file)?;
+            }
+            let weight_type = file.u8()?;
             let weight = if weight_type == 0 {
-                let a = Pmx::read_int(file, bone_index_size);
+                let a = Pmx::read_int(file, bone_index_size)?;
                 VertexWeight::One(a)
             } else if weight_type == 1 {
-                let a = Pmx::read_int(file, bone_index_size);
-                let b = Pmx::read_int(file, bone_index_size);
-                let weight = file.read_f32::<LE>().unwrap();
+                let a = Pmx::read_int(file, bone_index_size)?;
+                let b = Pmx::read_int(file, bone_index_size)?;
+                let weight = file.f32()?;
                 VertexWeight::Two(a, b, weight)
             } else if weight_type == 2 {
-                let a = Pmx::read_int(file, bone_index_size);
-                let b = Pmx::read_int(file, bone_index_size);
-                let c = Pmx::read_int(file, bone_index_size);
-                let d = Pmx::read_int(file, bone_index_size);
+                let a = Pmx::read_int(file, bone_index_size)?;
+                let b = Pmx::read_int(file, bone_index_size)?;
+                let c = Pmx::read_int(file, bone_index_size)?;
+                let d = Pmx::read_int(file, bone_index_size)?;
                 let index = ivec4(a, b, c, d);
-                let weight = read_vec4f(file);
+                let weight = read_vec4f(file)?;
                 VertexWeight::Four(index, weight)
             } else if weight_type == 3 {
-                let a = Pmx::read_int(file, bone_index_size);
-                let b = Pmx::read_int(file, bone_index_size);
-                let weight = file.read_f32::<LE>().unwrap();
-                let c = read_vec3f(file);
-                let r0 = read_vec3f(file);
-                let r1 = read_vec3f(file);
+                let a = Pmx::read_int(file, bone_index_size)?;
+                let b = Pmx::read_int(file, bone_index_size)?;
+                let weight = file.f32()?;
+                let c = read_vec3f(file)?;
+                let r0 = read_vec3f(file)?;
+                let r1 = read_vec3f(file)?;
                 VertexWeight::Sphere(a, b, weight, c, r0, r1)
             } else if weight_type == 4 {
-                let a = Pmx::read_int(file, bone_index_size);
-                let b = Pmx::read_int(file, bone_index_size);
-                let c = Pmx::read_int(file, bone_index_size);
-                let d = Pmx::read_int(file, bone_index_size);
+                let a = Pmx::read_int(file, bone_index_size)?;
+                let b = Pmx::read_int(file, bone_index_size)?;
+                let c = Pmx::read_int(file, bone_index_size)?;
+                let d = Pmx::read_int(file, bone_index_size)?;
                 let index = ivec4(a, b, c, d);
-                let weight = read_vec4f(file);
+                let weight = read_vec4f(file)?;
                 VertexWeight::Quat(index, weight)
             } else {
-                unreachable!()
+                return Err(Error::UnknownWeightType { offset: file.position() - 1, value: weight_type });
             };
-            let edge_scale = file.read_f32::<LE>().unwrap();
+            let edge_scale = file.f32()?;
             vct.push(Vertex {
                 pos,
                 nrm,
                 uv,
+                appendix_uv,
                 weight,
                 edge_scale,
             })
         }
-        vct
+        Ok(vct)
     }
     
-    fn read_int(file: &mut Cursor<Vec<u8>>, index_size: u8) -> i32 {
+    fn read_int(file: &mut Cursor<Vec<u8>>, index_size: u8) -> Result<i32> {
         match index_size {
-            1 => file.read_i8().unwrap() as i32,
-            2 => file.read_i16::<LE>().unwrap() as i32,
-            4 => file.read_i32::<LE>().unwrap(),
-            _ => unreachable!(),
+            1 => Ok(file.i8()? as i32),
+            2 => Ok(file.i16()? as i32),
+            4 => file.i32(),
+            _ => Err(Error::InvalidValue { offset: file.position(), what: "index size" }),
         }
         
     }
+    /// Vertex indices are unsigned at 1 and 2 bytes, unlike other indices.
+    fn read_vertex_index(file: &mut Cursor<Vec<u8>>, index_size: u8) -> Result<u32> {
+        match index_size {
+            1 => Ok(file.u8()? as u32),
+            2 => Ok(file.u16()? as u32),
+            4 => file.u32(),
+            _ => Err(Error::InvalidValue { offset: file.position(), what: "index size" }),
+        }
+    }
+    /// Clamps a count read from the file so a corrupt value cannot trigger a
+    /// huge allocation before the reads run out of data.
+    fn capacity(file: &Cursor<Vec<u8>>, len: u32) -> usize {
+        let remaining = (file.get_ref().len() as u64).saturating_sub(file.position());
+        (len as u64).min(remaining) as usize
+    }
+    fn read_index_size(file: &mut Cursor<Vec<u8>>) -> Result<u8> {
+        match file.u8()? {
+            size @ (1 | 2 | 4) => Ok(size),
+            _ => Err(Error::InvalidValue { offset: file.position() - 1, what: "index size" }),
+        }
+    }
 
     pub fn scale(&mut self, scale: f32) {
         for v in &mut self.verts {
@@ -1128,6 +1519,14 @@ impl Pmx {
                 b.local_axis = Some((x * scale, z * scale));
             }
         }
+        for m in &mut self.morphs {
+            match &mut m.morph {
+                Morph::Vertex(v) => v.iter_mut().for_each(|item| item.trans *= scale),
+                Morph::Bone(v) => v.iter_mut().for_each(|item| item.trans *= scale),
+                Morph::Rigidbody(v) => v.iter_mut().for_each(|item| item.trans_speed *= scale),
+                _ => {},
+            }
+        }
         for r in &mut self.rigidbodys {
             r.size *= scale;
             r.pos *= scale;
@@ -1220,6 +1619,30 @@ impl Pmx {
                 z.z *= -1.0;
             }
         }
+        for m in &mut self.morphs {
+            match &mut m.morph {
+                Morph::Vertex(v) => {
+                    for item in v {
+                        item.trans.z *= -1.0;
+                    }
+                },
+                Morph::Bone(v) => {
+                    for item in v {
+                        item.trans.z *= -1.0;
+                        item.rot.x *= -1.0;
+                        item.rot.y *= -1.0;
+                    }
+                },
+                Morph::Rigidbody(v) => {
+                    for item in v {
+                        item.trans_speed.z *= -1.0;
+                        item.rot_torque.x *= -1.0;
+                        item.rot_torque.y *= -1.0;
+                    }
+                },
+                _ => {},
+            }
+        }
         for r in &mut self.rigidbodys {
             r.pos.z *= -1.0;
             r.rot.x *= -1.0;
@@ -1242,3 +1665,318 @@ impl Pmx {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model() -> Pmx {
+        Pmx {
+            name: "model".to_string(),
+            name_en: "model".to_string(),
+            comment: String::new(),
+            comment_en: String::new(),
+            appendix_uv_count: 0,
+            verts: Vec::new(),
+            faces: Vec::new(),
+            texs: Vec::new(),
+            mats: Vec::new(),
+            bones: vec![Bone { name: "center".to_string(), ..Default::default() }, Bone { name: "arm".to_string(), parent_index: Some(0), ..Default::default() }],
+            iks: Vec::new(),
+            morphs: vec![MorphInfo { name: "smile".to_string(), name_en: String::new(), panel: 3, category: 1, morph: Morph::Vertex(Vec::new()) }],
+            display_frames: Vec::new(),
+            rigidbodys: Vec::new(),
+            joints: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn display_frames_round_trip() {
+        let mut pmx = model();
+        pmx.display_frames = pmx.default_display_frames();
+        pmx.display_frames.push(DisplayFrame {
+            name: "腕".to_string(),
+            name_en: "arms".to_string(),
+            deletable: false,
+            morph_items: vec![DisplayFrameIndex::Bone(1), DisplayFrameIndex::Morph(0)],
+        });
+        let read = Pmx::read(pmx.write()).unwrap();
+        assert_eq!(format!("{:?}", read.display_frames), format!("{:?}", pmx.display_frames));
+        assert!(matches!(read.display_frames[1].morph_items[..], [DisplayFrameIndex::Morph(0)]));
+    }
+
+    fn vertex(weight: VertexWeight) -> Vertex {
+        Vertex {
+            pos: Vec3::ZERO,
+            nrm: Vec3::Y,
+            uv: Vec2::ZERO,
+            appendix_uv: [Vec4::ZERO; 4],
+            weight,
+            edge_scale: 1.0,
+        }
+    }
+
+    fn version(data: &[u8]) -> f32 {
+        f32::from_le_bytes(data[4..8].try_into().unwrap())
+    }
+
+    #[test]
+    fn version_follows_2_1_features() {
+        let pmx = model();
+        let v2_0 = pmx.write();
+        assert_eq!(version(&v2_0), 2.0);
+
+        let mut flip = model();
+        flip.morphs.push(MorphInfo { name: "flip".to_string(), name_en: String::new(), panel: 4, category: 9, morph: Morph::Flip(vec![MorphFlipItem { index: 0, affect: 1.0 }]) });
+        let data = flip.write();
+        assert_eq!(version(&data), 2.1);
+        // Only the empty soft body table follows the joints.
+        assert_eq!(&data[data.len() - 4..], [0; 4]);
+        assert_eq!(Pmx::read(data).unwrap().morphs.len(), 2);
+
+        let mut impulse = model();
+        impulse.morphs[0].morph = Morph::Rigidbody(Vec::new());
+        assert_eq!(version(&impulse.write()), 2.1);
+
+        let mut qdef = model();
+        qdef.verts.push(vertex(VertexWeight::Quat(IVec4::new(0, 1, -1, -1), vec4(0.5, 0.5, 0.0, 0.0))));
+        let data = qdef.write();
+        assert_eq!(version(&data), 2.1);
+        assert!(matches!(Pmx::read(data).unwrap().verts[0].weight, VertexWeight::Quat(..)));
+    }
+
+    #[test]
+    fn bones_round_trip() {
+        let default_flags = Bone::default().bone_flags;
+        let mut pmx = model();
+        // A stale IK flag on a bone without an IK block is dropped.
+        pmx.bones[0].bone_flags |= BoneFlags::IK;
+        pmx.bones[1].bone_tail_pos = BoneTailPos::Bone(2);
+        pmx.bones[1].local_axis = Some((Vec3::X, Vec3::Z));
+        pmx.bones.push(Bone {
+            name: "foot".to_string(),
+            parent_index: Some(1),
+            layer: 1,
+            bone_flags: default_flags | BoneFlags::INHERIT_TRANSLATION,
+            inherit: Some((1, 0.5)),
+            fixed_axis: Some(Vec3::Y),
+            ..Default::default()
+        });
+        pmx.bones.push(Bone {
+            name: "foot IK".to_string(),
+            parent_index: Some(0),
+            pos: vec3(1.0, 0.0, 2.0),
+            bone_tail_pos: BoneTailPos::Pos(vec3(0.0, 0.0, 1.0)),
+            inherit: Some((0, 1.0)),
+            external_parent: Some(7),
+            ..Default::default()
+        });
+        pmx.iks.push(Ik {
+            bone: 3,
+            effector: 2,
+            loop_count: 40,
+            limit_angle: 2.0,
+            ik_joints: vec![
+                IkJoint { bone: 1, limit: Some((vec3(-3.0, 0.0, 0.0), vec3(-0.01, 0.0, 0.0))) },
+                IkJoint { bone: 0, limit: None },
+            ],
+        });
+        let expected_flags = [
+            default_flags,
+            default_flags | BoneFlags::INDEXED_TAIL_BONE | BoneFlags::LOCAL_AXIS,
+            default_flags | BoneFlags::INHERIT_TRANSLATION | BoneFlags::FIXED_AXIS,
+            // An inherit without either flag inherits rotation.
+            default_flags | BoneFlags::INHERIT_ROTATION | BoneFlags::EXTERNAL_PARENT | BoneFlags::IK,
+        ];
+        for (i, (b, flags)) in pmx.bones.iter().zip(expected_flags).enumerate() {
+            assert_eq!(pmx.bone_flags_for(i, b), flags);
+        }
+
+        for options in [WriteOptions::default(), WriteOptions { compact_indices: true, ..Default::default() }] {
+            let read = Pmx::read(pmx.write_with(&options)).unwrap();
+            let mut expected = pmx.bones.clone();
+            for (b, flags) in expected.iter_mut().zip(expected_flags) {
+                b.bone_flags = flags;
+            }
+            assert_eq!(format!("{:?}", read.bones), format!("{:?}", expected));
+
+            let [ik] = &read.iks[..] else { panic!("expected one IK") };
+            assert_eq!((ik.bone, ik.effector, ik.loop_count, ik.limit_angle), (3, 2, 40, 2.0));
+            assert_eq!(ik.ik_joints.len(), 2);
+            assert_eq!(ik.ik_joints[0].bone, 1);
+            assert_eq!(ik.ik_joints[0].limit, Some((vec3(-3.0, 0.0, 0.0), vec3(-0.01, 0.0, 0.0))));
+            assert_eq!((ik.ik_joints[1].bone, ik.ik_joints[1].limit), (0, None));
+        }
+    }
+
+    #[test]
+    fn weights_round_trip() {
+        let (c, r0, r1) = (vec3(0.0, 1.0, 0.0), vec3(0.0, 1.5, 0.5), vec3(0.0, 0.5, -0.5));
+        let mut pmx = model();
+        pmx.verts = vec![
+            vertex(VertexWeight::Two(0, 1, 0.25)),
+            vertex(VertexWeight::Sphere(1, 0, 0.75, c, r0, r1)),
+            vertex(VertexWeight::Quat(ivec4(0, 1, -1, -1), vec4(0.5, 0.5, 0.0, 0.0))),
+            vertex(VertexWeight::Four(ivec4(0, 1, 0, 1), vec4(0.1, 0.2, 0.3, 0.4))),
+            // One or two influences adding up to one are compacted.
+            vertex(VertexWeight::Four(ivec4(1, 0, 0, 0), vec4(1.0, 0.0, 0.0, 0.0))),
+            vertex(VertexWeight::Four(ivec4(0, 1, 2, 3), vec4(0.0, 0.25, 0.0, 0.75))),
+            // Two influences that fall short of one by more than the tolerance
+            // would deform differently as BDEF2, so they stay BDEF4.
+            vertex(VertexWeight::Four(ivec4(0, 1, -1, -1), vec4(0.5, 0.5 - 2e-5, 0.0, 0.0))),
+        ];
+        let read = Pmx::read(pmx.write()).unwrap();
+        let weights: Vec<VertexWeight> = read.verts.iter().map(|v| v.weight).collect();
+        assert!(matches!(weights[0], VertexWeight::Two(0, 1, 0.25)));
+        assert!(matches!(weights[1], VertexWeight::Sphere(1, 0, 0.75, ..)));
+        let VertexWeight::Sphere(_, _, _, read_c, read_r0, read_r1) = weights[1] else { unreachable!() };
+        assert_eq!((read_c, read_r0, read_r1), (c, r0, r1));
+        assert!(matches!(weights[2], VertexWeight::Quat(bi, bw) if bi == ivec4(0, 1, -1, -1) && bw == vec4(0.5, 0.5, 0.0, 0.0)));
+        assert!(matches!(weights[3], VertexWeight::Four(bi, bw) if bi == ivec4(0, 1, 0, 1) && bw == vec4(0.1, 0.2, 0.3, 0.4)));
+        assert!(matches!(weights[4], VertexWeight::One(1)));
+        assert!(matches!(weights[5], VertexWeight::Two(1, 3, 0.25)));
+        assert!(matches!(weights[6], VertexWeight::Four(bi, bw) if bi == ivec4(0, 1, -1, -1) && bw == vec4(0.5, 0.5 - 2e-5, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn compact_indices_round_trip() {
+        for encoding in [TextEncoding::Utf8, TextEncoding::Utf16] {
+            for (bone_count, vert_count, bone_size, vert_size) in [(128, 256, 1, 1), (129, 257, 2, 2)] {
+                let mut pmx = model();
+                pmx.name = "モデル".to_string();
+                pmx.bones = (0..bone_count).map(|i| Bone { name: format!("骨{}", i), ..Default::default() }).collect();
+                let last_bone = bone_count as i32 - 1;
+                pmx.verts = (0..vert_count).map(|_| vertex(VertexWeight::Two(0, last_bone, 0.5))).collect();
+                let last_vert = vert_count as u32 - 1;
+                pmx.faces = vec![[0, 1, last_vert]];
+                pmx.morphs[0].morph = Morph::Vertex(vec![MorphVertexItem { index: last_vert, trans: Vec3::X }]);
+
+                let data = pmx.write_with(&WriteOptions { encoding, compact_indices: true });
+                assert_eq!(data[9], (encoding == TextEncoding::Utf8) as u8);
+                assert_eq!((data[11], data[14]), (vert_size, bone_size));
+                let read = Pmx::read(data).unwrap();
+                assert_eq!(read.name, "モデル");
+                assert_eq!(read.bones.len(), bone_count);
+                assert_eq!(read.bones[bone_count - 1].name, format!("骨{}", bone_count - 1));
+                assert_eq!(read.faces, [[0, 1, last_vert]]);
+                assert!(matches!(read.verts[vert_count - 1].weight, VertexWeight::Two(0, b, _) if b == last_bone));
+                assert!(matches!(&read.morphs[0].morph, Morph::Vertex(v) if v[0].index == last_vert));
+            }
+        }
+    }
+
+    #[test]
+    fn appendix_uvs_round_trip() {
+        let mut pmx = model();
+        pmx.appendix_uv_count = 2;
+        let mut v = vertex(VertexWeight::One(0));
+        v.appendix_uv = [vec4(0.1, 0.2, 0.3, 0.4), vec4(-1.0, 0.5, 2.0, 0.0), Vec4::ONE, Vec4::ONE];
+        pmx.verts.push(v);
+        let read = Pmx::read(pmx.write()).unwrap();
+        assert_eq!(read.appendix_uv_count, 2);
+        // Channels past the count are not written and read back as zero.
+        assert_eq!(read.verts[0].appendix_uv, [vec4(0.1, 0.2, 0.3, 0.4), vec4(-1.0, 0.5, 2.0, 0.0), Vec4::ZERO, Vec4::ZERO]);
+        assert_eq!(read.verts[0].edge_scale, 1.0);
+    }
+
+    fn morph(name: &str, category: i8, morph: Morph) -> MorphInfo {
+        MorphInfo { name: name.to_string(), name_en: name.to_string(), panel: 4, category, morph }
+    }
+
+    /// A model with one morph of each kind, a rigid body and a joint.
+    fn physics_model() -> Pmx {
+        let mut pmx = model();
+        pmx.verts = vec![vertex(VertexWeight::One(0)), vertex(VertexWeight::One(1))];
+        pmx.mats.push(Mat::default());
+        pmx.morphs = vec![
+            morph("group", 0, Morph::Group(vec![MorphGroupItem { index: 1, affect: 0.5 }, MorphGroupItem { index: 2, affect: 1.0 }])),
+            morph("vertex", 1, Morph::Vertex(vec![MorphVertexItem { index: 1, trans: vec3(0.1, 0.2, 0.3) }])),
+            morph("bone", 2, Morph::Bone(vec![MorphBoneItem { index: 1, trans: vec3(1.0, 2.0, 3.0), rot: vec4(0.1, 0.2, 0.3, 0.9) }])),
+            morph("uv2", 5, Morph::Uv(vec![MorphUvItem { index: 0, trans: vec4(0.5, -0.5, 0.0, 1.0) }])),
+            morph("mat", 8, Morph::Mat(vec![MorphMatItem {
+                index: 0,
+                blend_mode: BlendMode::Add,
+                diffuse: vec4(0.1, 0.2, 0.3, 0.4),
+                specular: vec3(0.5, 0.6, 0.7),
+                specularity: 8.0,
+                ambient: vec3(0.9, 1.0, 1.1),
+                edge_color: vec4(1.2, 1.3, 1.4, 1.5),
+                edge_size: 1.6,
+                texture_tint: vec4(1.7, 1.8, 1.9, 2.0),
+                environment_tint: vec4(2.1, 2.2, 2.3, 2.4),
+                toon_tint: vec4(2.5, 2.6, 2.7, 2.8),
+            }])),
+            morph("flip", 9, Morph::Flip(vec![MorphFlipItem { index: 1, affect: 1.0 }])),
+            morph("impulse", 10, Morph::Rigidbody(vec![MorphRigidbodyItem {
+                index: 0,
+                local: true,
+                trans_speed: vec3(0.0, 1.0, 2.0),
+                rot_torque: vec3(3.0, 4.0, 5.0),
+            }])),
+        ];
+        pmx.rigidbodys.push(Rigidbody {
+            name: "arm".to_string(),
+            name_en: "arm".to_string(),
+            bone: 1,
+            group: 2,
+            collision_group: 0xfffe,
+            shape: RigidbodyShape::Capsule,
+            size: vec3(0.5, 2.0, 0.0),
+            pos: vec3(1.0, 2.0, 3.0),
+            rot: vec3(0.1, 0.2, 0.3),
+            mass: 1.5,
+            linear_damping: 0.5,
+            angular_damping: 0.25,
+            restitution: 0.125,
+            friction: 0.75,
+            mode: RigidbodyMode::DynamicsPassRotation,
+        });
+        pmx.joints.push(Joint {
+            name: "shoulder".to_string(),
+            name_en: "shoulder".to_string(),
+            category: 0,
+            rigidbody_a: -1,
+            rigidbody_b: 0,
+            pos: vec3(1.0, 2.0, 3.0),
+            rot: vec3(0.1, 0.2, 0.3),
+            pos_min: vec3(-1.0, -2.0, -3.0),
+            pos_max: vec3(1.0, 2.0, 3.0),
+            rot_min: vec3(-0.5, -0.25, 0.0),
+            rot_max: vec3(0.5, 0.25, 0.0),
+            pos_spring: vec3(10.0, 20.0, 30.0),
+            rot_spring: vec3(40.0, 50.0, 60.0),
+        });
+        pmx
+    }
+
+    #[test]
+    fn morphs_and_physics_round_trip() {
+        let pmx = physics_model();
+        for options in [WriteOptions::default(), WriteOptions { encoding: TextEncoding::Utf16, compact_indices: true }] {
+            let read = Pmx::read(pmx.write_with(&options)).unwrap();
+            assert_eq!(format!("{:?}", read.morphs), format!("{:?}", pmx.morphs));
+            assert_eq!(format!("{:?}", read.rigidbodys), format!("{:?}", pmx.rigidbodys));
+            assert_eq!(format!("{:?}", read.joints), format!("{:?}", pmx.joints));
+        }
+    }
+
+    #[test]
+    fn scale_and_mirror_morphs_and_physics() {
+        let mut pmx = physics_model();
+        pmx.scale(2.0);
+        pmx.right_hand();
+        let Morph::Vertex(v) = &pmx.morphs[1].morph else { unreachable!() };
+        assert_eq!(v[0].trans, vec3(0.2, 0.4, -0.6));
+        let Morph::Bone(v) = &pmx.morphs[2].morph else { unreachable!() };
+        assert_eq!((v[0].trans, v[0].rot), (vec3(2.0, 4.0, -6.0), vec4(-0.1, -0.2, 0.3, 0.9)));
+        // UV offsets are not positions.
+        let Morph::Uv(v) = &pmx.morphs[3].morph else { unreachable!() };
+        assert_eq!(v[0].trans, vec4(0.5, -0.5, 0.0, 1.0));
+        let Morph::Rigidbody(v) = &pmx.morphs[6].morph else { unreachable!() };
+        assert_eq!((v[0].trans_speed, v[0].rot_torque), (vec3(0.0, 2.0, -4.0), vec3(-3.0, -4.0, 5.0)));
+        let r = &pmx.rigidbodys[0];
+        assert_eq!((r.size, r.pos, r.rot), (vec3(1.0, 4.0, 0.0), vec3(2.0, 4.0, -6.0), vec3(-0.1, -0.2, 0.3)));
+        let j = &pmx.joints[0];
+        assert_eq!((j.pos, j.rot), (vec3(2.0, 4.0, -6.0), vec3(-0.1, -0.2, 0.3)));
+    }
+}
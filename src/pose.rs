@@ -0,0 +1,267 @@
+use glam::*;
+
+use crate::anm::{Animation, Sampling};
+use crate::error::{Error, Result};
+use crate::ktmdl::{KTModel, KTSubMesh};
+
+/// What a bone takes where its track has no value: no track at all, or
+/// before the first key of a sparse one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Fallback {
+    /// Identity rotation and zero translation, as `EvalDance` uses. A child
+    /// bone then sits on its parent's head.
+    #[default]
+    Identity,
+    /// The bone's bind transform relative to its parent, so untracked bones
+    /// keep the rest pose.
+    Bind,
+}
+
+/// A skeleton posed by one frame of an animation.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Pose {
+    /// Posed model-space transform of each bone, the animated counterpart
+    /// of `KTModel::bone_matrix`.
+    pub world: Vec<Mat4>,
+    /// `world[i] * bone_matrix[i].inverse()`, which moves bind-pose
+    /// geometry bound to bone `i` into this pose.
+    pub skinning: Vec<Mat4>,
+}
+
+impl Pose {
+    /// The bind pose of `model`.
+    pub fn bind(model: &KTModel) -> Self {
+        Pose {
+            world: model.bone_matrix.clone(),
+            skinning: vec![Mat4::IDENTITY; model.bone_matrix.len()],
+        }
+    }
+
+    /// Poses `model` at `frame` of `anm` as `EvalDance` in `Dance.cpp` does:
+    /// a track value replaces the bone's local bind transform, or for a root
+    /// bone is applied on top of its bind transform, and a bone without a
+    /// value gets identity.
+    pub fn evaluate(model: &KTModel, anm: &Animation, frame: u32) -> Result<Self> {
+        Self::sample(model, anm, frame as f32, &Sampling::default(), Fallback::Identity)
+    }
+
+    /// Like [`Pose::evaluate`] at fractional `time`, in `.anm` frames, with
+    /// `fallback` for bones without a value.
+    pub fn sample(model: &KTModel, anm: &Animation, time: f32, sampling: &Sampling, fallback: Fallback) -> Result<Self> {
+        let bone_count = model.bone_matrix.len();
+        if anm.bones.len() != bone_count {
+            return Err(Error::InvalidValue { offset: 0x20, what: "bone count (does not match .model)" });
+        }
+        let frame_count = anm.frame_count();
+        let mut world = Vec::<Mat4>::with_capacity(bone_count);
+        for (i, tracks) in anm.bones.iter().enumerate() {
+            let rot = tracks.rotation.as_ref().and_then(|t| t.sample(time, sampling, frame_count));
+            let trans = tracks.translation.as_ref().and_then(|t| t.sample(time, sampling, frame_count));
+            let g = match model.bone_parent[i] {
+                Some(p) if p < i => {
+                    let (bind_rot, bind_trans) = match fallback {
+                        Fallback::Identity => (Quat::IDENTITY, Vec3::ZERO),
+                        Fallback::Bind => {
+                            let (_, r, t) = model.bone_local_matrix(i).to_scale_rotation_translation();
+                            (r, t)
+                        },
+                    };
+                    world[p] * Mat4::from_rotation_translation(rot.unwrap_or(bind_rot), trans.unwrap_or(bind_trans))
+                },
+                Some(_) => return Err(Error::InvalidValue { offset: 0x18, what: "bone order (parent after child)" }),
+                None => {
+                    let local = Mat4::from_rotation_translation(rot.unwrap_or(Quat::IDENTITY), trans.unwrap_or(Vec3::ZERO));
+                    model.bone_matrix[i] * local
+                },
+            };
+            world.push(g);
+        }
+        let skinning = world.iter().zip(&model.bone_matrix).map(|(g, m)| *g * m.inverse()).collect();
+        Ok(Pose { world, skinning })
+    }
+
+    /// Posed position of each bone's head.
+    pub fn bone_positions(&self) -> Vec<Vec3> {
+        self.world.iter().map(|m| m.w_axis.truncate()).collect()
+    }
+
+    /// Rotation and head position of each bone relative to its parent, for
+    /// skeletons such as PMX and BVH whose bones all rest in world
+    /// orientation. `space` maps model space into the target space and may
+    /// scale or mirror it. Root bones are relative to the origin.
+    pub fn parent_relative(&self, model: &KTModel, space: Mat4) -> Vec<(Quat, Vec3)> {
+        let space_inv = space.inverse();
+        let mut world_rot = Vec::with_capacity(self.skinning.len());
+        let mut head = Vec::with_capacity(self.skinning.len());
+        for (s, p) in self.skinning.iter().zip(&model.bone_pos) {
+            let s = space * *s * space_inv;
+            world_rot.push(s.to_scale_rotation_translation().1);
+            head.push(s.transform_point3(space.transform_point3(*p)));
+        }
+        (0..self.skinning.len()).map(|i| match model.bone_parent[i] {
+            Some(p) => {
+                let inv = world_rot[p].inverse();
+                ((inv * world_rot[i]).normalize(), inv * (head[i] - head[p]))
+            },
+            None => (world_rot[i].normalize(), head[i]),
+        }).collect()
+    }
+
+    /// Blend of the skinning matrices of `bone_index` by `bone_weight`.
+    /// Indices outside the skeleton are skipped.
+    fn blend(&self, bone_index: IVec4, bone_weight: Vec4) -> Mat4 {
+        let mut m = Mat4::ZERO;
+        for j in 0..4 {
+            if let Some(s) = usize::try_from(bone_index[j]).ok().and_then(|b| self.skinning.get(b)) {
+                m += *s * bone_weight[j];
+            }
+        }
+        m
+    }
+
+    /// Deforms the submeshes of `model` into this pose with linear blend
+    /// skinning. Positions are moved as points; normals, tangents and
+    /// bitangents as directions, renormalised afterwards.
+    pub fn skin(&self, model: &KTModel) -> Vec<KTSubMesh> {
+        let mut meshs = model.meshs.clone();
+        for v in meshs.iter_mut().flat_map(|m| m.verts.iter_mut()) {
+            let m = self.blend(v.bone_index, v.bone_weight);
+            v.pos = m.transform_point3(v.pos);
+            v.norm = m.transform_vector3(v.norm).normalize_or_zero();
+            v.tang = m.transform_vector3(v.tang).normalize_or_zero();
+            v.bitang = m.transform_vector3(v.bitang).normalize_or_zero();
+        }
+        meshs
+    }
+
+    /// Bounds of the skinned vertices of `model`, or `None` if it has none.
+    pub fn bounding_box(&self, model: &KTModel) -> Option<(Vec3, Vec3)> {
+        model.meshs.iter().flat_map(|m| &m.verts).fold(None, |bounds, v| {
+            let p = self.blend(v.bone_index, v.bone_weight).transform_point3(v.pos);
+            Some(match bounds {
+                Some((min, max)) => (p.min(min), p.max(max)),
+                None => (p, p),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anm::{BoneTracks, Key, Track, TRACK_FLOAT_TRANSLATION, TRACK_ROTATION};
+    use crate::ktmdl::{KTSubMesh, KTVertex};
+
+    fn track<T: Copy>(kind: u16, sparse: bool, keys: &[(u32, T)]) -> Option<Track<T>> {
+        Some(Track {
+            kind,
+            interpolation: if sparse { 0 } else { 1 },
+            unknown: 0,
+            keys: keys.iter().map(|&(frame, value)| Key { frame, value }).collect(),
+        })
+    }
+
+    /// A root, a chain of two below it and a leaf without tracks, all with
+    /// bind rotations so local and world axes differ.
+    fn skeleton() -> (KTModel, Animation) {
+        let pos = [vec3(0.0, 1.0, 0.0), vec3(0.0, 2.0, 0.0), vec3(1.0, 2.0, 0.5), vec3(0.5, 1.0, 0.0)];
+        let rot = [Quat::from_rotation_y(0.5), Quat::from_rotation_z(0.3), Quat::from_rotation_x(-0.7), Quat::IDENTITY];
+        let vertex = |p: Vec3, b: IVec4, w: Vec4| KTVertex { pos: p, bone_index: b, bone_weight: w, norm: Vec3::Y, tang: Vec3::X, bitang: Vec3::Z, uv: Vec2::ZERO };
+        let model = KTModel {
+            bone_names: (0..4).map(|i| format!("bone{}", i)).collect(),
+            bone_pos: pos.to_vec(),
+            bone_parent: vec![None, Some(0), Some(1), Some(0)],
+            bone_matrix: (0..4).map(|i| Mat4::from_rotation_translation(rot[i], pos[i])).collect(),
+            bone_unknown: Vec::new(),
+            meshs: vec![KTSubMesh {
+                verts: vec![
+                    vertex(vec3(0.2, 2.5, 0.1), ivec4(1, 2, 0, 0), vec4(0.6, 0.4, 0.0, 0.0)),
+                    vertex(vec3(0.7, 1.1, 0.0), ivec4(3, 0, 0, 0), vec4(1.0, 0.0, 0.0, 0.0)),
+                    vertex(vec3(1.0, 2.2, 0.4), ivec4(0, 1, 2, 3), vec4(0.1, 0.2, 0.3, 0.4)),
+                ],
+                face: vec![[0, 1, 2]],
+            }],
+        };
+        let anm = Animation {
+            max_frame: 3,
+            bones: vec![
+                BoneTracks {
+                    rotation: track(TRACK_ROTATION, false, &[(0, Quat::from_rotation_x(0.2)), (1, Quat::from_rotation_x(0.4))]),
+                    translation: track(TRACK_FLOAT_TRANSLATION, false, &[(0, vec3(0.0, 0.1, 0.0))]),
+                },
+                BoneTracks {
+                    // Nothing before frame 2.
+                    rotation: track(TRACK_ROTATION, true, &[(2, Quat::from_rotation_y(1.0))]),
+                    translation: track(TRACK_FLOAT_TRANSLATION, false, &[(0, vec3(0.1, 1.0, 0.0)), (1, vec3(0.0, 1.2, 0.1)), (2, vec3(0.0, 1.0, 0.0))]),
+                },
+                BoneTracks {
+                    rotation: track(TRACK_ROTATION, false, &[(0, Quat::from_rotation_z(-0.5)), (1, Quat::IDENTITY), (2, Quat::from_rotation_z(0.5))]),
+                    translation: None,
+                },
+                BoneTracks::default(),
+            ],
+            header: Vec::new(),
+        };
+        (model, anm)
+    }
+
+    /// `EvalDance` transcribed line by line: the skinning matrix of every
+    /// bone at `frame`, with `ReadDanceAnm`'s identity and zero defaults.
+    fn eval_dance(model: &KTModel, anm: &Animation, frame: u32) -> Vec<Mat4> {
+        let mut ms: Vec<Mat4> = Vec::new();
+        for i in 0..model.bone_matrix.len() {
+            let m = model.bone_matrix[i];
+            let rotation = anm.rotation_at(i, frame);
+            let trans = anm.translation_at(i, frame);
+            let mut mat_quat = Mat4::from_translation(trans) * Mat4::from_quat(rotation);
+            if let Some(p) = model.bone_parent[i] {
+                let tmp = (model.bone_matrix[p].inverse() * m).inverse();
+                mat_quat = tmp * mat_quat;
+            }
+            let mut msi = m * mat_quat * m.inverse();
+            if let Some(p) = model.bone_parent[i] {
+                msi = ms[p] * msi;
+            }
+            ms.push(msi);
+        }
+        ms
+    }
+
+    #[test]
+    fn evaluate_matches_eval_dance() {
+        let (model, anm) = skeleton();
+        for frame in 0..5 {
+            let pose = Pose::evaluate(&model, &anm, frame).unwrap();
+            let ms = eval_dance(&model, &anm, frame);
+            for (i, (a, b)) in pose.skinning.iter().zip(&ms).enumerate() {
+                assert!(a.abs_diff_eq(*b, 1e-5), "frame {} bone {}: {:?} != {:?}", frame, i, a, b);
+            }
+
+            let skinned = pose.skin(&model);
+            for (v, s) in model.meshs[0].verts.iter().zip(&skinned[0].verts) {
+                let m: Mat4 = (0..4).map(|j| ms[v.bone_index[j] as usize] * v.bone_weight[j]).fold(Mat4::ZERO, |a, b| a + b);
+                assert!(s.pos.abs_diff_eq(m.transform_point3(v.pos), 1e-5));
+                assert!(s.norm.abs_diff_eq(m.transform_vector3(v.norm).normalize(), 1e-5));
+            }
+        }
+    }
+
+    #[test]
+    fn untracked_bones_follow_fallback() {
+        let (model, anm) = skeleton();
+        let still = Animation { bones: vec![BoneTracks::default(); 4], ..anm };
+
+        // `EvalDance` collapses an untracked child onto its parent's head.
+        let pose = Pose::evaluate(&model, &still, 0).unwrap();
+        let heads = pose.bone_positions();
+        assert!(heads[0].abs_diff_eq(model.bone_pos[0], 1e-6));
+        for (i, head) in heads.iter().enumerate().skip(1) {
+            assert!(head.abs_diff_eq(model.bone_pos[0], 1e-6), "bone {}: {:?}", i, head);
+        }
+
+        let pose = Pose::sample(&model, &still, 0.0, &Sampling::default(), Fallback::Bind).unwrap();
+        for (i, s) in pose.skinning.iter().enumerate() {
+            assert!(s.abs_diff_eq(Mat4::IDENTITY, 1e-5), "bone {}: {:?}", i, s);
+        }
+    }
+}